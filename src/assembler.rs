@@ -0,0 +1,238 @@
+use instructions::{Cond,Instr,encode};
+use registers::{Reg8,Reg16,Indirect};
+
+// Assembles one instruction written the way `Instr` displays it, e.g.
+// "LD A,(0xff00+0x44)" or "JR NZ,-5". Numbers are decimal unless they
+// start with 0x or $.
+pub fn assemble(text: &str) -> Result<Vec<u8>, String> {
+    parse(text).map(|instr| encode(&instr))
+}
+
+const MNEMONICS: [&str; 43] = [
+    "NOP", "STOP", "HALT", "RLCA", "RLA", "RRCA", "RRA", "DAA", "CPL", "CCF", "SCF",
+    "RETI", "DI", "EI", "LD", "JR", "JP", "CALL", "RET", "RST", "ADD", "ADC", "SUB",
+    "SBC", "AND", "XOR", "OR", "CP", "INC", "DEC", "PUSH", "POP", "BIT", "RES", "SET",
+    "RLC", "RRC", "RL", "RR", "SLA", "SRA", "SRL", "SWAP",
+];
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum Operand {
+    Reg8(Reg8),
+    Reg16(Reg16),
+    Indirect(Indirect),
+    Cond(Cond),
+    Imm(i32),
+    // (nnnn)
+    Mem(u16),
+    // (0xff00+nn)
+    IO(u8),
+    // (0xff00+C)
+    Ioc,
+    // SP+n, as in LD HL,SP+n
+    StackOffset(i8),
+}
+
+pub fn parse(text: &str) -> Result<Instr, String> {
+    let text = text.trim().to_uppercase();
+    let (mnemonic, rest) = match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], &text[i..]),
+        None => (&text[..], ""),
+    };
+    let rest: String = rest.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut operands = Vec::new();
+    if !rest.is_empty() {
+        for operand in rest.split(',') {
+            operands.push(parse_operand(operand)?);
+        }
+    }
+    let bad = || Err(format!("Invalid operands for {}", mnemonic));
+
+    use self::Operand as Op;
+    let instr = match (mnemonic, &operands[..]) {
+        ("NOP", &[]) => Instr::Nop,
+        ("STOP", &[]) => Instr::Stop,
+        ("HALT", &[]) => Instr::Halt,
+        ("RLCA", &[]) => Instr::RotateALeft,
+        ("RLA", &[]) => Instr::RotateALeftCarry,
+        ("RRCA", &[]) => Instr::RotateARight,
+        ("RRA", &[]) => Instr::RotateARightCarry,
+        ("DAA", &[]) => Instr::DecimalAdjust,
+        ("CPL", &[]) => Instr::Complement,
+        ("CCF", &[]) => Instr::ComplementCarry,
+        ("SCF", &[]) => Instr::SetCarry,
+        ("RETI", &[]) => Instr::RetI,
+        ("DI", &[]) => Instr::DisableInterrupts,
+        ("EI", &[]) => Instr::EnableInterrupts,
+
+        ("LD", &[Op::Mem(addr), Op::Reg16(Reg16::SP)]) => Instr::StoreSP(addr),
+        ("LD", &[Op::Reg16(Reg16::SP), Op::Reg16(Reg16::HL)]) => Instr::LoadStackHL,
+        ("LD", &[Op::Reg16(Reg16::HL), Op::StackOffset(offset)]) => Instr::LoadLocalAddr(offset),
+        ("LD", &[Op::Reg16(reg), Op::Imm(imm)]) if reg != Reg16::AF => Instr::LoadImm16(reg, imm16(imm)?),
+        ("LD", &[Op::Indirect(indirect), Op::Reg8(Reg8::A)]) => Instr::StoreA(indirect),
+        ("LD", &[Op::Reg8(Reg8::A), Op::Indirect(indirect)]) => Instr::LoadA(indirect),
+        ("LD", &[Op::IO(imm), Op::Reg8(Reg8::A)]) => Instr::StoreIO(imm),
+        ("LD", &[Op::Reg8(Reg8::A), Op::IO(imm)]) => Instr::LoadIO(imm),
+        ("LD", &[Op::Ioc, Op::Reg8(Reg8::A)]) => Instr::StoreIOC,
+        ("LD", &[Op::Reg8(Reg8::A), Op::Ioc]) => Instr::LoadIOC,
+        ("LD", &[Op::Mem(addr), Op::Reg8(Reg8::A)]) => Instr::StoreGlobal(addr),
+        ("LD", &[Op::Reg8(Reg8::A), Op::Mem(addr)]) => Instr::LoadGlobal(addr),
+        ("LD", &[Op::Reg8(reg), Op::Imm(imm)]) => Instr::LoadImm8(reg, imm8(imm)?),
+        // LD (HL),(HL) is where HALT lives.
+        ("LD", &[Op::Reg8(Reg8::HL), Op::Reg8(Reg8::HL)]) => return bad(),
+        ("LD", &[Op::Reg8(to), Op::Reg8(from)]) => Instr::RegCopy(to, from),
+
+        ("JR", &[Op::Imm(offset)]) => Instr::JumpRelative(offset8(offset)?, Cond::Always),
+        ("JR", &[cond, Op::Imm(offset)]) => Instr::JumpRelative(offset8(offset)?, condition(cond)?),
+        ("JP", &[Op::Reg8(Reg8::HL)]) | ("JP", &[Op::Reg16(Reg16::HL)]) => Instr::JumpHL,
+        ("JP", &[Op::Imm(addr)]) => Instr::Jump(imm16(addr)?, Cond::Always),
+        ("JP", &[cond, Op::Imm(addr)]) => Instr::Jump(imm16(addr)?, condition(cond)?),
+        ("CALL", &[Op::Imm(addr)]) => Instr::Call(imm16(addr)?, Cond::Always),
+        ("CALL", &[cond, Op::Imm(addr)]) => Instr::Call(imm16(addr)?, condition(cond)?),
+        ("RET", &[]) => Instr::Ret(Cond::Always),
+        ("RET", &[cond]) => Instr::Ret(condition(cond)?),
+        ("RST", &[Op::Imm(addr)]) if addr % 8 == 0 && (0..0x40).contains(&addr) => Instr::Restart(addr as u8 / 8),
+
+        ("ADD", &[Op::Reg16(Reg16::HL), Op::Reg16(reg)]) if reg != Reg16::AF => Instr::AddHL(reg),
+        ("ADD", &[Op::Reg16(Reg16::SP), Op::Imm(offset)]) => Instr::StackAdjust(offset8(offset)?),
+        ("ADD", &[Op::Reg8(Reg8::A), Op::Reg8(reg)]) => Instr::Add(reg),
+        ("ADD", &[Op::Reg8(Reg8::A), Op::Imm(imm)]) => Instr::AddImm(imm8(imm)?),
+        ("ADC", &[Op::Reg8(Reg8::A), Op::Reg8(reg)]) => Instr::AddCarry(reg),
+        ("ADC", &[Op::Reg8(Reg8::A), Op::Imm(imm)]) => Instr::AddCarryImm(imm8(imm)?),
+        ("SBC", &[Op::Reg8(Reg8::A), Op::Reg8(reg)]) => Instr::SubCarry(reg),
+        ("SBC", &[Op::Reg8(Reg8::A), Op::Imm(imm)]) => Instr::SubCarryImm(imm8(imm)?),
+        ("SUB", _) | ("AND", _) | ("XOR", _) | ("OR", _) | ("CP", _) => {
+            // The accumulator may be written out, as in "CP A,0x10".
+            let operand = match &operands[..] {
+                &[operand] | &[Op::Reg8(Reg8::A), operand] => operand,
+                _ => return bad(),
+            };
+            match (mnemonic, operand) {
+                ("SUB", Op::Reg8(reg)) => Instr::Sub(reg),
+                ("SUB", Op::Imm(imm)) => Instr::SubImm(imm8(imm)?),
+                ("AND", Op::Reg8(reg)) => Instr::And(reg),
+                ("AND", Op::Imm(imm)) => Instr::AndImm(imm8(imm)?),
+                ("XOR", Op::Reg8(reg)) => Instr::Xor(reg),
+                ("XOR", Op::Imm(imm)) => Instr::XorImm(imm8(imm)?),
+                ("OR", Op::Reg8(reg)) => Instr::Or(reg),
+                ("OR", Op::Imm(imm)) => Instr::OrImm(imm8(imm)?),
+                ("CP", Op::Reg8(reg)) => Instr::Comp(reg),
+                ("CP", Op::Imm(imm)) => Instr::CompImm(imm8(imm)?),
+                _ => return bad(),
+            }
+        },
+
+        ("INC", &[Op::Reg8(reg)]) => Instr::Inc8(reg),
+        ("DEC", &[Op::Reg8(reg)]) => Instr::Dec8(reg),
+        ("INC", &[Op::Reg16(reg)]) if reg != Reg16::AF => Instr::Inc16(reg),
+        ("DEC", &[Op::Reg16(reg)]) if reg != Reg16::AF => Instr::Dec16(reg),
+        ("PUSH", &[Op::Reg16(reg)]) if reg != Reg16::SP => Instr::Push(reg),
+        ("POP", &[Op::Reg16(reg)]) if reg != Reg16::SP => Instr::Pop(reg),
+
+        ("BIT", &[Op::Imm(bit), Op::Reg8(reg)]) => Instr::Bit(reg, bit_index(bit)?),
+        ("RES", &[Op::Imm(bit), Op::Reg8(reg)]) => Instr::Reset(reg, bit_index(bit)?),
+        ("SET", &[Op::Imm(bit), Op::Reg8(reg)]) => Instr::Set(reg, bit_index(bit)?),
+        ("RLC", &[Op::Reg8(reg)]) => Instr::RotateLeft(reg),
+        ("RRC", &[Op::Reg8(reg)]) => Instr::RotateRight(reg),
+        ("RL", &[Op::Reg8(reg)]) => Instr::RotateLeftCarry(reg),
+        ("RR", &[Op::Reg8(reg)]) => Instr::RotateRightCarry(reg),
+        ("SLA", &[Op::Reg8(reg)]) => Instr::ShiftLeft(reg),
+        ("SRA", &[Op::Reg8(reg)]) => Instr::ShiftRightArith(reg),
+        ("SRL", &[Op::Reg8(reg)]) => Instr::ShiftRightLogical(reg),
+        ("SWAP", &[Op::Reg8(reg)]) => Instr::SwapBytes(reg),
+
+        _ if MNEMONICS.contains(&mnemonic) => return bad(),
+        _ => return Err(format!("Unknown instruction: {}", mnemonic)),
+    };
+    Ok(instr)
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    let operand = match text {
+        "A" => Operand::Reg8(Reg8::A),
+        "B" => Operand::Reg8(Reg8::B),
+        // C is also a condition; `condition` sorts that out.
+        "C" => Operand::Reg8(Reg8::C),
+        "D" => Operand::Reg8(Reg8::D),
+        "E" => Operand::Reg8(Reg8::E),
+        "H" => Operand::Reg8(Reg8::H),
+        "L" => Operand::Reg8(Reg8::L),
+        "(HL)" => Operand::Reg8(Reg8::HL),
+        "AF" => Operand::Reg16(Reg16::AF),
+        "BC" => Operand::Reg16(Reg16::BC),
+        "DE" => Operand::Reg16(Reg16::DE),
+        "HL" => Operand::Reg16(Reg16::HL),
+        "SP" => Operand::Reg16(Reg16::SP),
+        "(BC)" => Operand::Indirect(Indirect::BC),
+        "(DE)" => Operand::Indirect(Indirect::DE),
+        "(HL+)" | "(HLI)" => Operand::Indirect(Indirect::HLPlus),
+        "(HL-)" | "(HLD)" => Operand::Indirect(Indirect::HLMinus),
+        "Z" => Operand::Cond(Cond::Z),
+        "NZ" => Operand::Cond(Cond::NZ),
+        "NC" => Operand::Cond(Cond::NC),
+        "(0XFF00+C)" | "($FF00+C)" | "(C)" => Operand::Ioc,
+        _ if text.starts_with("SP+") || text.starts_with("SP-") =>
+            Operand::StackOffset(offset8(number(&text[2..])?)?),
+        _ if text.starts_with("(0XFF00+") || text.starts_with("($FF00+") => {
+            let inner = &text[text.find('+').unwrap() + 1..text.len() - 1];
+            Operand::IO(imm8(number(inner)?)?)
+        },
+        _ if text.starts_with('(') && text.ends_with(')') =>
+            Operand::Mem(imm16(number(&text[1..text.len() - 1])?)?),
+        _ => Operand::Imm(number(text)?),
+    };
+    Ok(operand)
+}
+
+// Parses 0x-prefixed or $-prefixed hex, or signed decimal.
+fn number(text: &str) -> Result<i32, String> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0X").or_else(|| digits.strip_prefix('$')) {
+        i32::from_str_radix(hex, 16)
+    } else {
+        digits.parse()
+    };
+    match value {
+        Ok(value) if negative => Ok(-value),
+        Ok(value) => Ok(value),
+        Err(_) => Err(format!("Invalid number: {}", text)),
+    }
+}
+
+fn imm8(value: i32) -> Result<u8, String> {
+    match value {
+        -128 ..= 255 => Ok(value as u8),
+        _ => Err(format!("{} doesn't fit in a byte", value)),
+    }
+}
+
+fn imm16(value: i32) -> Result<u16, String> {
+    match value {
+        -32768 ..= 65535 => Ok(value as u16),
+        _ => Err(format!("{} doesn't fit in 16 bits", value)),
+    }
+}
+
+fn offset8(value: i32) -> Result<i8, String> {
+    match value {
+        -128 ..= 127 => Ok(value as i8),
+        _ => Err(format!("Offset {} is out of range", value)),
+    }
+}
+
+fn bit_index(value: i32) -> Result<u8, String> {
+    match value {
+        0 ..= 7 => Ok(value as u8),
+        _ => Err(format!("Invalid bit: {}", value)),
+    }
+}
+
+fn condition(operand: Operand) -> Result<Cond, String> {
+    match operand {
+        Operand::Cond(cond) => Ok(cond),
+        Operand::Reg8(Reg8::C) => Ok(Cond::C),
+        _ => Err("Expected a condition".to_string()),
+    }
+}
@@ -3,27 +3,59 @@ use std::path::Path;
 
 use cartridge::Cartridge;
 use cpu::Interrupt;
-use debugger::DebugState;
 use gpu::{BgMap,Gpu};
 use joypad::{Joypad,Button};
-use serial::Serial;
+use serial::{Link,Serial,Sink};
 use timer::Timer;
 use SCREEN_BUFFER_SIZE;
 
 const BOOT_ROM_SIZE: usize = 0x100;
 const INTERNAL_RAM_SIZE: usize = 0x2000;
 const HIGH_RAM_SIZE: usize = 0x7f;
+const WAVE_RAM_SIZE: usize = 0x10;
 
-struct WatchInfo {
-    enabled: bool,
-    write_buffer: Vec<u16>
-}
+// Bits of the sound registers (0xff10-0xff2f) that always read as 1.
+// With no sound hardware modelled every register reads as if it were
+// zero, which leaves NR52 reporting the APU as switched off.
+const SOUND_READ_MASK: [u8; 0x20] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf, 0xff, 0x3f, 0x00,
+    0xff, 0xbf, 0x7f, 0xff, 0x9f, 0xff, 0xbf, 0xff,
+    0xff, 0x00, 0x00, 0xbf, 0x00, 0x00, 0x70, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
 
-impl WatchInfo {
-    pub fn new() -> Self {
-        WatchInfo {
-            enabled: false,
-            write_buffer: vec![],
+// The CPU's view of the rest of the system. Reads and writes each
+// happen on a machine cycle the CPU has already ticked. Peeks are for
+// the debugger and tracing: they have no side effects and see memory
+// as it is, without bus conflicts such as OAM DMA's. Everything else is
+// optional, so a bus that is just memory only needs the first four.
+pub trait MemoryBus {
+    fn peek(&self, addr: u16) -> u8;
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, val: u8);
+    // Advances the system by one machine cycle. Returns true if a
+    // frame was finished.
+    fn tick(&mut self) -> bool;
+
+    fn get_highest_priority_interrupt(&self) -> Option<Interrupt> { None }
+    fn clear_interrupt(&mut self, _interrupt: Interrupt) {}
+    fn joypad_line_low(&self) -> bool { false }
+    fn reset_divider(&mut self) {}
+    fn warn_unmapped(&self, _addr: u16) -> bool { false }
+    // The ROM bank mapped at `addr`, for the debugger.
+    fn rom_bank(&self, addr: u16) -> usize { if addr < 0x4000 { 0 } else { 1 } }
+    // The cartridge RAM bank mapped at 0xa000-0xbfff.
+    fn ram_bank(&self) -> usize { 0 }
+    // The byte at `addr` in a ROM or cartridge RAM bank that might not
+    // be mapped. Other addresses have no banks.
+    fn peek_bank(&self, _bank: usize, addr: u16) -> u8 { self.peek(addr) }
+
+    // Whichever bank is mapped at `addr`.
+    fn bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000 ..= 0x7fff => self.rom_bank(addr),
+            0xa000 ..= 0xbfff => self.ram_bank(),
+            _ => 0
         }
     }
 }
@@ -39,9 +71,10 @@ pub struct Bus {
     timer: Timer,
     internal_ram: [u8; INTERNAL_RAM_SIZE],
     high_ram: [u8; HIGH_RAM_SIZE],
+    wave_ram: [u8; WAVE_RAM_SIZE],
     interrupts_flag: Interrupt,
     interrupts_enable: u8,
-    watch_info: WatchInfo
+    strict: bool,
 }
 
 impl Bus {
@@ -49,10 +82,13 @@ impl Bus {
         let mut file = ::std::fs::File::open(cartridge_file)?;
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
-        Ok(Bus {
+        Ok(Bus::with_rom(buffer))
+    }
+    pub fn with_rom(rom: Vec<u8>) -> Self {
+        Bus {
             bootrom_active: true,
             bootrom: *include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/data/boot.rom")),
-            cartridge: Cartridge::new(buffer),
+            cartridge: Cartridge::new(rom),
             dma: Dma::new(),
             gpu: Gpu::new(),
             joypad: Joypad::new(),
@@ -60,22 +96,41 @@ impl Bus {
             timer: Timer::new(),
             internal_ram: [0; INTERNAL_RAM_SIZE],
             high_ram: [0; HIGH_RAM_SIZE],
+            wave_ram: [0; WAVE_RAM_SIZE],
             interrupts_flag: Interrupt::empty(),
             interrupts_enable: 0,
-            watch_info: WatchInfo::new(),
-        })
+            strict: false,
+        }
     }
     pub fn r8(&self, addr: u16) -> u8 {
+        // While OAM DMA is running it owns the main bus, so the CPU
+        // sees the byte being transferred everywhere except HRAM and
+        // the IO registers.
+        if self.dma.is_active() {
+            match addr {
+                0xfe00 ..= 0xfe9f => return 0xff,
+                0x0000 ..= 0xfeff => return self.dma.value,
+                _ => {}
+            }
+        }
+        self.read_memory(addr)
+    }
+    // Reads without DMA bus conflicts. This isn't called read so it
+    // can't be confused with MemoryBus::read, which has them.
+    fn read_memory(&self, addr: u16) -> u8 {
         match addr {
-            0x0000 ... 0x00ff if self.bootrom_active => self.bootrom[addr as usize],
-            0x0000 ... 0x7fff => self.cartridge.r8(addr),
-            0x8000 ... 0x97ff => self.gpu.read_tile_ram(addr - 0x8000),
-            0x9800 ... 0x9bff => self.gpu.read_bg_map(addr - 0x9800, BgMap::Map1),
-            0x9c00 ... 0x9fff => self.gpu.read_bg_map(addr - 0x9c00, BgMap::Map2),
-            0xc000 ... 0xdfff => self.internal_ram[addr as usize - 0xc000],
-            0xe000 ... 0xfdff => self.internal_ram[addr as usize - 0xe000],
-            0xfe00 ... 0xfe9f => self.gpu.read_sprite_ram(addr - 0xfe00),
-            0xfea0 ... 0xfeff => 0,
+            0x0000 ..= 0x00ff if self.bootrom_active => self.bootrom[addr as usize],
+            0x0000 ..= 0x7fff => self.cartridge.r8(addr),
+            0x8000 ..= 0x97ff => self.gpu.read_tile_ram(addr - 0x8000),
+            0x9800 ..= 0x9bff => self.gpu.read_bg_map(addr - 0x9800, BgMap::Map1),
+            0x9c00 ..= 0x9fff => self.gpu.read_bg_map(addr - 0x9c00, BgMap::Map2),
+            0xa000 ..= 0xbfff => self.cartridge.read_ram(addr - 0xa000),
+            0xc000 ..= 0xdfff => self.internal_ram[addr as usize - 0xc000],
+            0xe000 ..= 0xfdff => self.internal_ram[addr as usize - 0xe000],
+            0xfe00 ..= 0xfe9f => self.gpu.read_sprite_ram(addr - 0xfe00),
+            // On DMG the unusable area reads as 0 unless the PPU has
+            // locked OAM, in which case it reads 0xff like OAM does.
+            0xfea0 ..= 0xfeff => if self.gpu.oam_blocked() { 0xff } else { 0 },
             0xff00            => self.joypad.read(),
             0xff01            => self.serial.get_transfer(),
             0xff02            => self.serial.get_control(),
@@ -83,12 +138,9 @@ impl Bus {
             0xff05            => self.timer.get_counter(),
             0xff06            => self.timer.get_modulo(),
             0xff07            => self.timer.get_control(),
-            0xff0f            => self.interrupts_flag.bits(),
-            0xff10 ... 0xff14 |
-            0xff16 ... 0xff1e |
-            0xff20 ... 0xff26 |
-            0xff30 ... 0xff3f => { // unimplemented!("Read IO reg (sound): {:04x}", addr);
-                                   0},
+            0xff0f            => self.interrupts_flag.bits() | 0xe0,
+            0xff10 ..= 0xff2f => SOUND_READ_MASK[addr as usize - 0xff10],
+            0xff30 ..= 0xff3f => self.wave_ram[addr as usize - 0xff30],
             0xff40            => self.gpu.get_control(),
             0xff41            => self.gpu.get_stat(),
             0xff42            => self.gpu.get_scroll_y(),
@@ -101,23 +153,30 @@ impl Bus {
             0xff49            => self.gpu.get_obj_palette(1),
             0xff4a            => self.gpu.get_window_y(),
             0xff4b            => self.gpu.get_window_x(),
-            0xff00 ... 0xff7f => 0xff,
-            0xff80 ... 0xfffe => self.high_ram[addr as usize - 0xff80],
+            0xff00 ..= 0xff7f => 0xff,
+            0xff80 ..= 0xfffe => self.high_ram[addr as usize - 0xff80],
             0xffff            => self.interrupts_enable,
-            _ => unimplemented!("Unknown address: {:04x}", addr)
         }
     }
     pub fn w8(&mut self, addr: u16, val: u8) {
+        if self.dma.is_active() && addr < 0xff00 {
+            return;
+        }
+        self.write_memory(addr, val);
+    }
+    // Writes without DMA bus conflicts.
+    fn write_memory(&mut self, addr: u16, val: u8) {
         match addr {
-            0x0000 ... 0x00FF if self.bootrom_active => panic!("Writing to boot rom"),
-            0x0000 ... 0x7fff => self.cartridge.w8(addr, val),
-            0x8000 ... 0x97ff => self.gpu.write_tile_ram(addr - 0x8000, val),
-            0x9800 ... 0x9bff => self.gpu.write_bg_map(addr - 0x9800, BgMap::Map1, val),
-            0x9c00 ... 0x9fff => self.gpu.write_bg_map(addr - 0x9c00, BgMap::Map2, val),
-            0xc000 ... 0xdfff => self.internal_ram[addr as usize - 0xc000] = val,
-            0xe000 ... 0xfdff => self.internal_ram[addr as usize - 0xe000] = val,
-            0xfe00 ... 0xfe9f => self.gpu.write_sprite_ram(addr - 0xfe00, val),
-            0xfea0 ... 0xfeff => {},
+            // Writes under the boot ROM still reach the cartridge.
+            0x0000 ..= 0x7fff => self.cartridge.w8(addr, val),
+            0x8000 ..= 0x97ff => self.gpu.write_tile_ram(addr - 0x8000, val),
+            0x9800 ..= 0x9bff => self.gpu.write_bg_map(addr - 0x9800, BgMap::Map1, val),
+            0x9c00 ..= 0x9fff => self.gpu.write_bg_map(addr - 0x9c00, BgMap::Map2, val),
+            0xa000 ..= 0xbfff => self.cartridge.write_ram(addr - 0xa000, val),
+            0xc000 ..= 0xdfff => self.internal_ram[addr as usize - 0xc000] = val,
+            0xe000 ..= 0xfdff => self.internal_ram[addr as usize - 0xe000] = val,
+            0xfe00 ..= 0xfe9f => self.gpu.write_sprite_ram(addr - 0xfe00, val),
+            0xfea0 ..= 0xfeff => {},
             0xff00            => self.joypad.write(val),
             0xff01            => self.serial.set_transfer(val),
             0xff02            => self.serial.set_control(val),
@@ -126,10 +185,8 @@ impl Bus {
             0xff05            => self.timer.set_counter(val),
             0xff06            => self.timer.set_modulo(val),
             0xff07            => self.timer.set_control(val),
-            0xff10 ... 0xff14 |
-            0xff16 ... 0xff1e |
-            0xff20 ... 0xff26 |
-            0xff30 ... 0xff3f => {},//unimplemented!("Write IO reg (sound): {:04x} = {}", addr, val),
+            0xff10 ..= 0xff2f => {},
+            0xff30 ..= 0xff3f => self.wave_ram[addr as usize - 0xff30] = val,
             0xff40            => self.gpu.set_control(val),
             0xff41            => self.gpu.set_stat(val),
             0xff42            => self.gpu.set_scroll_y(val),
@@ -143,16 +200,68 @@ impl Bus {
             0xff4a            => self.gpu.set_window_y(val),
             0xff4b            => self.gpu.set_window_x(val),
             0xff50            => self.bootrom_active = false,
-            0xff00 ... 0xff7f => {},
-            0xff80 ... 0xfffe => self.high_ram[addr as usize - 0xff80] = val,
+            0xff00 ..= 0xff7f => {},
+            0xff80 ..= 0xfffe => self.high_ram[addr as usize - 0xff80] = val,
             0xffff            => self.interrupts_enable = val,
-            _ => unimplemented!("Unknown address: {:04x}", addr)
         }
-        if self.watch_info.enabled {
-            self.watch_info.write_buffer.push(addr);
+    }
+
+    // Writes a byte for the debugger. ROM is changed in place rather
+    // than the write going to the memory controller, and DMA doesn't
+    // block it.
+    pub fn patch(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000 ..= 0x00ff if self.bootrom_active => self.bootrom[addr as usize] = val,
+            0x0000 ..= 0x7fff => self.cartridge.patch_rom(addr, val),
+            _ => self.write_memory(addr, val),
         }
     }
 
+    pub fn cartridge_ram(&self) -> &[u8] {
+        self.cartridge.ram()
+    }
+
+    // The rest of the system, for the debugger to inspect.
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+    pub fn gpu(&self) -> &Gpu {
+        &self.gpu
+    }
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+    pub fn dma(&self) -> &Dma {
+        &self.dma
+    }
+    // IE and IF.
+    pub fn interrupts_enabled(&self) -> Interrupt {
+        Interrupt::from_bits_truncate(self.interrupts_enable)
+    }
+    pub fn interrupts_requested(&self) -> Interrupt {
+        self.interrupts_flag
+    }
+
+    // Whether anything responds at this address. Reads from unmapped
+    // addresses still return a value, but strict mode reports them.
+    pub fn is_mapped(&self, addr: u16) -> bool {
+        match addr {
+            0xa000 ..= 0xbfff => self.cartridge.ram_accessible(),
+            0xfea0 ..= 0xfeff |
+            0xff03            |
+            0xff08 ..= 0xff0e |
+            0xff15            |
+            0xff1f            |
+            0xff27 ..= 0xff2f |
+            0xff4c ..= 0xff4f |
+            0xff51 ..= 0xff7f => false,
+            _ => true
+        }
+    }
+    pub fn set_strict(&mut self, value: bool) {
+        self.strict = value;
+    }
+
     pub fn r16(&self, addr: u16) -> u16 {
         let lo = self.r8(addr) as u16;
         let hi = self.r8(addr+1) as u16;
@@ -165,29 +274,66 @@ impl Bus {
         self.w8(addr+1, hi as u8);
     }
     pub fn key_down(&mut self, button: Button) {
-        self.joypad.key_down(button);
+        if self.joypad.key_down(button) {
+            self.interrupts_flag.insert(Interrupt::JOYPAD);
+        }
+    }
+    pub fn joypad_line_low(&self) -> bool {
+        self.joypad.read() & 0x0f != 0x0f
+    }
+    pub fn reset_divider(&mut self) {
+        self.timer.reset_divider();
     }
     pub fn key_up(&mut self, button: Button) {
         self.joypad.key_up(button);
     }
     pub fn update(&mut self, cycles: u32) -> bool {
         self.timer.update(cycles, &mut self.interrupts_flag);
+        self.serial.update(cycles, &mut self.interrupts_flag);
         self.update_dma(cycles);
         let redraw = self.gpu.update(cycles, &mut self.interrupts_flag);
         redraw
     }
+    pub fn connect_serial(&mut self, link: Link) {
+        self.serial.connect(link);
+    }
+    pub fn set_serial_sink(&mut self, sink: Sink) {
+        self.serial.set_sink(sink);
+    }
+    pub fn serial_output(&self) -> &[u8] {
+        self.serial.get_sink().contents()
+    }
+    pub fn serial_cable_transfer(&self) -> Option<u8> {
+        self.serial.cable_transfer()
+    }
+    pub fn complete_serial_transfer(&mut self, received: u8) {
+        self.serial.complete_transfer(received, &mut self.interrupts_flag);
+    }
+    pub fn receive_serial_transfer(&mut self, received: u8) -> u8 {
+        self.serial.receive_transfer(received, &mut self.interrupts_flag)
+    }
     fn update_dma(&mut self, cycles: u32) {
         for _ in 0..cycles / 4 {
+            if let Some(delay) = self.dma.start_delay {
+                if delay == 0 {
+                    // A restart replaces any transfer already in progress.
+                    self.dma.start_delay = None;
+                    self.dma.source = self.dma.source_for(self.dma.address);
+                    self.dma.progress = Some(0);
+                } else {
+                    self.dma.start_delay = Some(delay - 1);
+                }
+            }
             if let Some(offset) = self.dma.progress {
-                let base = (self.dma.address as u16) << 8;
-                let data = self.r8(base + offset);
+                let data = self.read_memory(self.dma.source + offset);
+                self.dma.value = data;
                 self.gpu.write_sprite_ram(offset, data);
                 self.dma.progress = if offset < 0x9f {
                     Some(offset + 1)
                 } else {
                     None
                 }
-            } else {
+            } else if self.dma.start_delay.is_none() {
                 return;
             }
         }
@@ -212,32 +358,156 @@ impl Bus {
     pub fn clear_interrupt(&mut self, interrupt: Interrupt) {
         self.interrupts_flag.remove(interrupt);
     }
-    pub fn set_watching(&mut self, value: bool) {
-        self.watch_info.enabled = value;
+}
+
+
+impl MemoryBus for Bus {
+    fn peek(&self, addr: u16) -> u8 {
+        self.read_memory(addr)
     }
-    pub fn check_watch_buffer(&mut self, debug: &mut DebugState) {
-        if debug.watchpoints.is_empty() {
-            return;
-        }
-        for addr in self.watch_info.write_buffer.drain(..) {
-            if debug.watchpoints.contains(&addr) {
-                debug.paused = true;
-            }
+    fn read(&mut self, addr: u16) -> u8 {
+        self.r8(addr)
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        self.w8(addr, val);
+    }
+    fn tick(&mut self) -> bool {
+        self.update(4)
+    }
+    fn get_highest_priority_interrupt(&self) -> Option<Interrupt> {
+        Bus::get_highest_priority_interrupt(self)
+    }
+    fn clear_interrupt(&mut self, interrupt: Interrupt) {
+        Bus::clear_interrupt(self, interrupt);
+    }
+    fn joypad_line_low(&self) -> bool {
+        Bus::joypad_line_low(self)
+    }
+    fn reset_divider(&mut self) {
+        Bus::reset_divider(self);
+    }
+    fn warn_unmapped(&self, addr: u16) -> bool {
+        self.strict && !self.is_mapped(addr)
+    }
+    fn rom_bank(&self, addr: u16) -> usize {
+        self.cartridge.bank_at(addr)
+    }
+    fn ram_bank(&self) -> usize {
+        self.cartridge.ram_bank()
+    }
+    fn peek_bank(&self, bank: usize, addr: u16) -> u8 {
+        match addr {
+            0x0000 ..= 0x7fff => self.cartridge.read_rom_bank(bank, addr),
+            0xa000 ..= 0xbfff => self.cartridge.read_ram_bank(bank, addr - 0xa000),
+            _ => self.read_memory(addr)
         }
     }
 }
 
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum BusCycle {
+    Internal,
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+// 64KiB of plain memory with nothing else attached, for running the
+// CPU on its own. It logs what happened on every machine cycle.
+pub struct FlatBus {
+    memory: Vec<u8>,
+    cycles: Vec<BusCycle>,
+    // Whether IE (0xffff) and IF (0xff0f) raise interrupts. Off by
+    // default, so that tests can put anything in those bytes.
+    interrupts: bool,
+}
 
+impl Default for FlatBus {
+    fn default() -> Self { FlatBus::new() }
+}
+
+impl FlatBus {
+    pub fn new() -> Self {
+        FlatBus {
+            memory: vec![0; 0x10000],
+            cycles: Vec::new(),
+            interrupts: false,
+        }
+    }
+    pub fn map_interrupts(&mut self) {
+        self.interrupts = true;
+    }
+    pub fn load(&mut self, addr: u16, data: &[u8]) {
+        for (i, &value) in data.iter().enumerate() {
+            self.memory[addr.wrapping_add(i as u16) as usize] = value;
+        }
+    }
+    pub fn cycles(&self) -> &[BusCycle] {
+        &self.cycles
+    }
+    pub fn clear_cycles(&mut self) {
+        self.cycles.clear();
+    }
+    fn record(&mut self, cycle: BusCycle) {
+        match self.cycles.last_mut() {
+            Some(last) if *last == BusCycle::Internal => *last = cycle,
+            _ => panic!("Bus access without a cycle: {:?}", cycle),
+        }
+    }
+}
+
+impl MemoryBus for FlatBus {
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+    fn read(&mut self, addr: u16) -> u8 {
+        let value = self.memory[addr as usize];
+        self.record(BusCycle::Read(addr, value));
+        value
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        self.memory[addr as usize] = val;
+        self.record(BusCycle::Write(addr, val));
+    }
+    fn tick(&mut self) -> bool {
+        self.cycles.push(BusCycle::Internal);
+        false
+    }
+    fn get_highest_priority_interrupt(&self) -> Option<Interrupt> {
+        if !self.interrupts {
+            return None;
+        }
+        let pending = self.memory[0xffff] & self.memory[0xff0f] & 0x1f;
+        let lowest = Interrupt::from_bits_truncate(pending & (!pending).wrapping_add(1));
+        if !lowest.is_empty() {
+            Some(lowest)
+        } else {
+            None
+        }
+    }
+    fn clear_interrupt(&mut self, interrupt: Interrupt) {
+        self.memory[0xff0f] &= !interrupt.bits();
+    }
+}
 
 pub struct Dma {
     address: u8,
+    source: u16,
+    value: u8,
+    start_delay: Option<u8>,
     progress: Option<u16>
 }
 
+impl Default for Dma {
+    fn default() -> Self { Dma::new() }
+}
+
 impl Dma {
     pub fn new() -> Self {
         Dma {
             address: 0,
+            source: 0,
+            value: 0xff,
+            start_delay: None,
             progress: None
         }
     }
@@ -245,7 +515,28 @@ impl Dma {
         self.address
     }
     pub fn set_address(&mut self, value: u8) {
+        // The transfer starts after a cycle of setup, during which
+        // any earlier transfer keeps running.
         self.address = value;
-        self.progress = Some(0);
+        self.start_delay = Some(1);
+    }
+    pub fn is_active(&self) -> bool {
+        self.progress.is_some()
+    }
+    // Whether a transfer has been asked for but hasn't started yet.
+    pub fn is_starting(&self) -> bool {
+        self.start_delay.is_some()
+    }
+    pub fn get_source(&self) -> u16 {
+        self.source
+    }
+    // How many bytes of the transfer have been copied so far.
+    pub fn get_progress(&self) -> Option<u16> {
+        self.progress
+    }
+    fn source_for(&self, address: u8) -> u16 {
+        // Sources above 0xdf wrap around onto work RAM.
+        let page = if address >= 0xe0 { address - 0x20 } else { address };
+        (page as u16) << 8
     }
 }
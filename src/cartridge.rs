@@ -1,10 +1,14 @@
-type Bank = u8;
+pub type Bank = u8;
 
-#[derive(Debug)]
-enum MemoryController {
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+const MBC2_RAM_SIZE: usize = 0x200;
+
+#[derive(Copy,Clone,Debug)]
+pub enum MemoryController {
     None,
-    MBC1(Bank),
-    MBC2, //MBC3, MBC5
+    MBC1 { rom_bank: Bank, upper_bits: Bank, ram_banking: bool },
+    MBC2(Bank), //MBC3, MBC5
 }
 
 impl Default for MemoryController {
@@ -22,20 +26,21 @@ struct CartridgeMode {
 
 impl CartridgeMode {
     pub fn new(spec: u8) -> Option<Self> {
+        let mbc1 = MemoryController::MBC1 { rom_bank: 1, upper_bits: 0, ram_banking: false };
         match spec {
             0x0 => Some(Default::default()),
-            0x1 => Some(CartridgeMode { mbc: MemoryController::MBC1(1),
+            0x1 => Some(CartridgeMode { mbc: mbc1,
                                         ..Default::default() }),
-            0x2 => Some(CartridgeMode { mbc: MemoryController::MBC1(1),
+            0x2 => Some(CartridgeMode { mbc: mbc1,
                                         has_ram: true,
                                         ..Default::default() }),
-            0x3 => Some(CartridgeMode { mbc: MemoryController::MBC1(1),
+            0x3 => Some(CartridgeMode { mbc: mbc1,
                                         has_ram: true,
                                         has_batt: true,
                                         ..Default::default() }),
-            0x5 => Some(CartridgeMode { mbc: MemoryController::MBC2,
+            0x5 => Some(CartridgeMode { mbc: MemoryController::MBC2(1),
                                         ..Default::default() }),
-            0x6 => Some(CartridgeMode { mbc: MemoryController::MBC2,
+            0x6 => Some(CartridgeMode { mbc: MemoryController::MBC2(1),
                                         has_batt: true,
                                         ..Default::default() }),
             0x8 => Some(CartridgeMode { has_ram: true,
@@ -50,51 +55,172 @@ impl CartridgeMode {
 
 pub struct Cartridge {
     data: Vec<u8>,
-    mode: CartridgeMode
+    mode: CartridgeMode,
+    ram: Vec<u8>,
+    ram_enabled: bool,
 }
 
 impl Cartridge {
     pub fn new(data: Vec<u8>) -> Self {
         let mode = CartridgeMode::new(data[0x147]).expect("Unknown cartridge type");
         let _rom_size = data[0x148];
-        let _ram_size = data[0x149];
+        let ram_size = match mode.mbc {
+            MemoryController::MBC2(_) => MBC2_RAM_SIZE,
+            _ if mode.has_ram => match data[0x149] {
+                0x1 => 0x800,
+                0x2 => RAM_BANK_SIZE,
+                0x3 => 4 * RAM_BANK_SIZE,
+                0x4 => 16 * RAM_BANK_SIZE,
+                0x5 => 8 * RAM_BANK_SIZE,
+                _ => 0
+            },
+            _ => 0
+        };
+        // Carts without a controller have nothing to enable their RAM.
+        let ram_enabled = matches!(mode.mbc, MemoryController::None);
         Cartridge {
-            data: data,
-            mode: mode
+            data,
+            mode,
+            ram: vec![0; ram_size],
+            ram_enabled,
         }
     }
     pub fn r8(&self, addr: u16) -> u8 {
-        if addr < 0x4000 {
-            self.data[addr as usize]
+        self.data.get(self.rom_offset(addr)).cloned().unwrap_or(0xff)
+    }
+    // Changes the ROM byte currently mapped at `addr`, for the debugger.
+    pub fn patch_rom(&mut self, addr: u16, val: u8) {
+        let offset = self.rom_offset(addr);
+        if let Some(byte) = self.data.get_mut(offset) {
+            *byte = val;
+        }
+    }
+    // The ROM bank mapped at `addr`.
+    pub fn bank_at(&self, addr: u16) -> usize {
+        let bank = if addr < 0x4000 {
+            match self.mode.mbc {
+                // In RAM banking mode the upper bits also switch bank 0.
+                MemoryController::MBC1 { upper_bits, ram_banking: true, .. } =>
+                    (upper_bits as usize) << 5,
+                _ => 0
+            }
         } else {
             match self.mode.mbc {
-                MemoryController::None => {
-                    self.data[addr as usize]
-                },
-                MemoryController::MBC1(bank) => {
-                    let bank_base_addr = (bank - 1) as usize * 0x4000;
-                    self.data[bank_base_addr + addr as usize]
-                },
-                _ => unimplemented!("Memory controller: {:?}", self.mode.mbc)
+                MemoryController::None => 1,
+                MemoryController::MBC1 { rom_bank, upper_bits, .. } =>
+                    (upper_bits as usize) << 5 | rom_bank as usize,
+                MemoryController::MBC2(bank) => bank as usize,
             }
+        };
+        bank % ::std::cmp::max(self.data.len() / ROM_BANK_SIZE, 1)
+    }
+    // The cartridge RAM bank mapped at 0xa000-0xbfff.
+    pub fn ram_bank(&self) -> usize {
+        match self.mode.mbc {
+            MemoryController::MBC1 { upper_bits, ram_banking: true, .. } => upper_bits as usize,
+            _ => 0
+        }
+    }
+    // Reads ROM from any bank, whether or not it's mapped, for the debugger.
+    pub fn read_rom_bank(&self, bank: usize, addr: u16) -> u8 {
+        let banks = ::std::cmp::max(self.data.len() / ROM_BANK_SIZE, 1);
+        let offset = bank % banks * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        self.data.get(offset).cloned().unwrap_or(0xff)
+    }
+    // Reads cartridge RAM from any bank, even while RAM is disabled.
+    pub fn read_ram_bank(&self, bank: usize, addr: u16) -> u8 {
+        if self.ram.is_empty() {
+            return 0xff;
         }
+        match self.mode.mbc {
+            MemoryController::MBC2(_) => 0xf0 | self.ram[addr as usize % MBC2_RAM_SIZE],
+            _ => self.ram[(bank * RAM_BANK_SIZE + addr as usize) % self.ram.len()],
+        }
+    }
+    // Where the byte mapped at `addr` lives in the ROM file.
+    fn rom_offset(&self, addr: u16) -> usize {
+        self.bank_at(addr) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1))
     }
     pub fn w8(&mut self, addr: u16, val: u8) {
         match self.mode.mbc {
             MemoryController::None => {},
-            MemoryController::MBC1(ref mut bank) => {
+            MemoryController::MBC1 { ref mut rom_bank, ref mut upper_bits, ref mut ram_banking } => {
+                match addr {
+                    0x0000 ..= 0x1fff => self.ram_enabled = val & 0xf == 0xa,
+                    0x2000 ..= 0x3fff => {
+                        let mut new_bank = val & 0x1f;
+                        if new_bank == 0 { new_bank = 1; }
+                        *rom_bank = new_bank;
+                    },
+                    0x4000 ..= 0x5fff => *upper_bits = val & 0x3,
+                    _ => *ram_banking = val & 0x1 != 0,
+                }
+            },
+            MemoryController::MBC2(ref mut bank) => {
                 match addr {
-                    0x0000 ... 0x1fff => unimplemented!("RAM enable"),
-                    0x2000 ... 0x3fff => {
-                        let mut new_bank = addr & 0x1f;
+                    // Address bit 8 selects between RAM enable and ROM bank.
+                    0x0000 ..= 0x3fff if addr & 0x100 == 0 => self.ram_enabled = val & 0xf == 0xa,
+                    0x0000 ..= 0x3fff => {
+                        let mut new_bank = val & 0xf;
                         if new_bank == 0 { new_bank = 1; }
-                        *bank = new_bank as u8;
+                        *bank = new_bank;
                     },
-                    _ => unimplemented!("BLAH BLAH")
+                    _ => {}
                 }
             },
-            _ => { unimplemented!("Bank switching: {:#04x} <- {}", addr, val); }
         }
     }
-
+    // The game's title from the header.
+    pub fn title(&self) -> String {
+        let title = self.data.get(0x134..0x144).unwrap_or(&[]);
+        title.iter().take_while(|&&c| c != 0).map(|&c| c as char).collect()
+    }
+    pub fn controller(&self) -> MemoryController {
+        self.mode.mbc
+    }
+    pub fn has_battery(&self) -> bool {
+        self.mode.has_batt
+    }
+    pub fn rom_banks(&self) -> usize {
+        ::std::cmp::max(self.data.len() / ROM_BANK_SIZE, 1)
+    }
+    // Carts with 2KiB of RAM count as one bank.
+    pub fn ram_banks(&self) -> usize {
+        self.ram.len().div_ceil(RAM_BANK_SIZE)
+    }
+    pub fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+    // All of the cartridge RAM, in every bank.
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    pub fn ram_accessible(&self) -> bool {
+        self.ram_enabled && !self.ram.is_empty()
+    }
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_accessible() {
+            return 0xff;
+        }
+        match self.mode.mbc {
+            // MBC2 has 512 half-bytes of RAM, repeated across the range.
+            MemoryController::MBC2(_) => 0xf0 | self.ram[addr as usize % MBC2_RAM_SIZE],
+            _ => self.ram[self.ram_offset(addr)],
+        }
+    }
+    pub fn write_ram(&mut self, addr: u16, val: u8) {
+        if !self.ram_accessible() {
+            return;
+        }
+        match self.mode.mbc {
+            MemoryController::MBC2(_) => self.ram[addr as usize % MBC2_RAM_SIZE] = val & 0xf,
+            _ => {
+                let offset = self.ram_offset(addr);
+                self.ram[offset] = val;
+            }
+        }
+    }
+    fn ram_offset(&self, addr: u16) -> usize {
+        (self.ram_bank() * RAM_BANK_SIZE + addr as usize) % self.ram.len()
+    }
 }
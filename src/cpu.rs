@@ -1,10 +1,11 @@
 use std::path::Path;
 
-use bus::Bus;
-use debugger::DebugState;
+use bus::{Bus,MemoryBus};
+use debugger::{Access,DebugState,Flow};
 use registers::{Registers,Reg8,Reg16,Indirect};
-use instructions::{Cond,Instr};
+use instructions::{Cond,Instr,decode_from};
 use joypad::Button;
+use trace::Trace;
 use SCREEN_BUFFER_SIZE;
 
 bitflags! {
@@ -17,207 +18,235 @@ bitflags! {
     }
 }
 
-pub struct Cpu {
+// A call the CPU has made and not yet returned from.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub struct CallFrame {
+    // The instruction that made the call, or was interrupted.
+    pub call_site: u16,
+    pub target: u16,
+    pub return_addr: u16,
+    // The ROM bank the return address is in.
+    pub return_bank: usize,
+    // Where the return address was pushed.
+    pub sp: u16,
+    pub interrupt: bool,
+}
+
+// Deeper than this, the oldest frames are forgotten.
+const MAX_CALL_DEPTH: usize = 1024;
+
+pub struct Cpu<B: MemoryBus = Bus> {
     pub reg: Registers,
-    pub bus: Bus,
+    pub bus: B,
     master_interrupt_flag: bool,
-    interrupts_buffer: Option<bool>,
+    // EI takes effect after the following instruction.
+    interrupts_delay: u8,
     halted: bool,
+    halt_bug: bool,
     stopped: bool,
     redraw: bool,
+    // Cycles taken by the current step.
+    cycles: u32,
+    // Address of the instruction being executed.
+    instr_pc: u16,
+    trace: Option<Trace>,
+    // Memory accesses made by the current step, recorded while there
+    // are watchpoints.
+    watching: bool,
+    accesses: Vec<Access>,
+    frames: u64,
+    call_stack: Vec<CallFrame>,
 }
 
 impl Cpu {
     pub fn new(cartridge_path: &Path) -> Self {
+        Cpu::with_bus(Bus::new(cartridge_path).expect("File not found"))
+    }
+    pub fn key_down(&mut self, button: Button) {
+        self.bus.key_down(button);
+    }
+    pub fn key_up(&mut self, button: Button) {
+        self.bus.key_up(button);
+    }
+    pub fn fill_screen_buffer(&self, buffer: &mut[u8; SCREEN_BUFFER_SIZE]) {
+        if self.stopped {
+            *buffer = [0xff; SCREEN_BUFFER_SIZE];
+        } else {
+            self.bus.fill_screen_buffer(buffer);
+        }
+    }
+}
+
+impl<B: MemoryBus> Cpu<B> {
+    pub fn with_bus(bus: B) -> Self {
         Cpu {
             reg: Registers::new(),
-            bus: Bus::new(cartridge_path).expect("File not found"),
+            bus,
             master_interrupt_flag: false,
-            interrupts_buffer: None,
+            interrupts_delay: 0,
             halted: false,
+            halt_bug: false,
             stopped: false,
-            redraw: false
+            redraw: false,
+            cycles: 0,
+            instr_pc: 0,
+            trace: None,
+            watching: false,
+            accesses: Vec::new(),
+            frames: 0,
+            call_stack: Vec::new(),
         }
     }
-    pub fn step(&mut self, debug: &mut DebugState) {
+    pub fn step(&mut self, debug: &mut DebugState) -> u32 {
         if self.stopped {
-            return;
+            if self.bus.joypad_line_low() {
+                self.stopped = false;
+            } else {
+                return 4;
+            }
         }
 
-        self.bus.set_watching(!debug.watchpoints.is_empty());
-        let interrupt = {
-            let i = self.bus.get_highest_priority_interrupt();
-            if i.is_some() {
-                self.halted = false;
-            }
-            if self.master_interrupt_flag { i } else { None }
-        };
+        self.watching = !debug.watchpoints.is_empty();
+        self.cycles = 0;
+        self.instr_pc = self.reg.pc;
+
+        let pending = self.bus.get_highest_priority_interrupt().is_some();
+        if pending && self.halted {
+            // Waking up from HALT takes an extra cycle.
+            self.halted = false;
+            self.tick();
+        }
 
-        let cycles = if let Some(interrupt) = interrupt {
-            self.bus.clear_interrupt(interrupt);
-            self.master_interrupt_flag = false;
-            let dest = match interrupt {
-                Interrupt::VBLANK   => 0x40,
-                Interrupt::LCD_STAT => 0x48,
-                Interrupt::TIMER    => 0x50,
-                Interrupt::SERIAL   => unimplemented!(), //0x58,
-                Interrupt::JOYPAD   => unimplemented!(), //0x60,
-                _ => unreachable!("Only one bit can be set.")
-            };
-            self.call(dest);
-            20
+        let sp = self.reg.sp;
+        let frames = self.frames;
+        let mut flow = None;
+        let interrupted = pending && self.master_interrupt_flag;
+        if interrupted {
+            self.dispatch_interrupt();
+            flow = Some(Flow::Call);
         } else if !self.halted{
+            if let Some(ref mut trace) = self.trace {
+                let pc = self.reg.pc;
+                let pcmem = [self.bus.peek(pc),
+                             self.bus.peek(pc.wrapping_add(1)),
+                             self.bus.peek(pc.wrapping_add(2)),
+                             self.bus.peek(pc.wrapping_add(3))];
+                trace.record(&self.reg, pcmem);
+            }
+            // Each byte is read on its own machine cycle and the
+            // decoder gets what the bus returned, bus conflicts and all.
+            // After the HALT bug the opcode is read without moving PC,
+            // so it is read again as the next byte of the instruction.
             let mut pc = self.reg.pc;
-            let instr = self.fetch(&mut pc);
+            let mut stall = self.halt_bug;
+            self.halt_bug = false;
+            let instr = decode_from(|| {
+                let value = self.fetch8(pc);
+                if stall {
+                    stall = false;
+                } else {
+                    pc = pc.wrapping_add(1);
+                }
+                value
+            });
             self.reg.pc = pc;
-            let cycles = self.exec(instr);
-            cycles
+            self.exec(instr);
+            // Conditional calls and returns only move SP when taken.
+            if self.reg.sp != sp {
+                flow = match instr {
+                    Instr::Call(..) | Instr::Restart(_) => Some(Flow::Call),
+                    Instr::Ret(_) | Instr::RetI => Some(Flow::Return),
+                    _ => None,
+                };
+            }
         } else {
-            4 // halted
-        };
+            self.tick(); // halted
+        }
 
-        if let Some(interrupt) = self.interrupts_buffer {
-            self.master_interrupt_flag = interrupt;
-            self.interrupts_buffer = None;
+        if self.interrupts_delay > 0 {
+            self.interrupts_delay -= 1;
+            if self.interrupts_delay == 0 {
+                self.master_interrupt_flag = true;
+            }
         }
-        self.update(cycles);
-        self.update_debugger(debug);
+        self.update_call_stack(flow, interrupted);
+        let new_frame = self.frames != frames;
+        self.update_debugger(debug, new_frame);
+        self.cycles
     }
-    #[inline(always)]
-    pub fn fetch(&self, cursor: &mut u16) -> Instr {
-        let opcode = self.imm8(cursor);
+    // Interrupt dispatch takes five cycles: two internal cycles, one
+    // for each byte of PC pushed, and one to jump to the handler. The
+    // handler is only chosen after the high byte of PC is pushed, so
+    // if that push overwrites IE the interrupt can change or be
+    // cancelled altogether, in which case we jump to 0x0000.
+    fn dispatch_interrupt(&mut self) {
+        self.master_interrupt_flag = false;
+        if self.halt_bug {
+            // The return address is the HALT itself.
+            self.halt_bug = false;
+            self.reg.pc = self.reg.pc.wrapping_sub(1);
+        }
+        self.tick();
+        self.tick();
 
-        let x = opcode >> 6;
-        let y = (opcode >> 3) & 7;
-        let z = opcode & 7;
+        let pc = self.reg.pc;
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        let sp = self.reg.sp;
+        self.write8(sp, (pc >> 8) as u8);
 
-        match (x,y,z) {
-            (0,0,0) => Instr::Nop,
-            (0,1,0) => Instr::StoreSP(self.imm16(cursor)),
-            (0,2,0) => Instr::Stop,
-            (0,3,0) => Instr::JumpRelative(self.imm8(cursor) as i8, Cond::Always),
-            (0,4...7,0) => Instr::JumpRelative(self.imm8(cursor) as i8, Cond::from(y-4)),
-            (0,_,1) if y % 2 == 0  => Instr::LoadImm16(Reg16::from(y, true), self.imm16(cursor)),
-            (0,_,1) => Instr::AddHL(Reg16::from(y, true)),
-            (0,_,2) if y % 2 == 0 => Instr::StoreA(Indirect::from(y)),
-            (0,_,2) => Instr::LoadA(Indirect::from(y)),
-            (0,_,3) if y % 2 == 0 => Instr::Inc16(Reg16::from(y, true)),
-            (0,_,3) => Instr::Dec16(Reg16::from(y, true)),
-            (0,_,4) => Instr::Inc8(Reg8::from(y)),
-            (0,_,5) => Instr::Dec8(Reg8::from(y)),
-            (0,_,6) => Instr::LoadImm8(Reg8::from(y), self.imm8(cursor)),
-            (0,0,7) => Instr::RotateALeft,
-            (0,1,7) => Instr::RotateARight,
-            (0,2,7) => Instr::RotateALeftCarry,
-            (0,3,7) => Instr::RotateARightCarry,
-            (0,4,7) => Instr::DecimalAdjust,
-            (0,5,7) => Instr::Complement,
-            (0,6,7) => Instr::SetCarry,
-            (0,7,7) => Instr::ComplementCarry,
-            (1,6,6) => Instr::Halt,
-            (1,_,_) => Instr::RegCopy(Reg8::from(y), Reg8::from(z)),
-            (2,0,_) => Instr::Add(Reg8::from(z)),
-            (2,1,_) => Instr::AddCarry(Reg8::from(z)),
-            (2,2,_) => Instr::Sub(Reg8::from(z)),
-            (2,3,_) => Instr::SubCarry(Reg8::from(z)),
-            (2,4,_) => Instr::And(Reg8::from(z)),
-            (2,5,_) => Instr::Xor(Reg8::from(z)),
-            (2,6,_) => Instr::Or(Reg8::from(z)),
-            (2,7,_) => Instr::Comp(Reg8::from(z)),
-            (3,0...3,0) => Instr::Ret(Cond::from(y)),
-            (3,4,0) => Instr::StoreIO(self.imm8(cursor)),
-            (3,5,0) => Instr::StackAdjust(self.imm8(cursor) as i8),
-            (3,6,0) => Instr::LoadIO(self.imm8(cursor)),
-            (3,7,0) => Instr::LoadLocalAddr(self.imm8(cursor) as i8),
-            (3,_,1) if y % 2 == 0 => Instr::Pop(Reg16::from(y, false)),
-            (3,1,1) => Instr::Ret(Cond::Always),
-            (3,3,1) => Instr::RetI,
-            (3,5,1) => Instr::JumpHL,
-            (3,7,1) => Instr::LoadStackHL,
-            (3,0...3,2) => Instr::Jump(self.imm16(cursor), Cond::from(y)),
-            (3,4,2) => Instr::StoreIOC,
-            (3,5,2) => Instr::StoreGlobal(self.imm16(cursor)),
-            (3,6,2) => Instr::LoadIOC,
-            (3,7,2) => Instr::LoadGlobal(self.imm16(cursor)),
-            (3,0,3) => Instr::Jump(self.imm16(cursor), Cond::Always),
-            (3,1,3) => {
-                let extended_opcode = self.imm8(cursor);
-                let operation = extended_opcode >> 6;
-                let y = (extended_opcode >> 3) & 7;
-                let reg = Reg8::from(extended_opcode & 7);
-                match operation {
-                    0 => { // Rotations
-                        match y {
-                            0 => Instr::RotateLeft(reg),
-                            1 => Instr::RotateRight(reg),
-                            2 => Instr::RotateLeftCarry(reg),
-                            3 => Instr::RotateRightCarry(reg),
-                            4 => Instr::ShiftLeft(reg),
-                            5 => Instr::ShiftRightArith(reg),
-                            6 => Instr::SwapBytes(reg),
-                            7 => Instr::ShiftRightLogical(reg),
-                            _ => unreachable!("Invalid rotation"),
-                        }
-                    },
-                    1 => Instr::Bit(reg, y),
-                    2 => Instr::Reset(reg, y),
-                    3 => Instr::Set(reg, y),
-                    _ => unreachable!("Invalid extended instruction")
+        let interrupt = self.bus.get_highest_priority_interrupt();
+
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        let sp = self.reg.sp;
+        self.write8(sp, pc as u8);
+
+        self.reg.pc = match interrupt {
+            Some(interrupt) => {
+                self.bus.clear_interrupt(interrupt);
+                match interrupt {
+                    Interrupt::VBLANK   => 0x40,
+                    Interrupt::LCD_STAT => 0x48,
+                    Interrupt::TIMER    => 0x50,
+                    Interrupt::SERIAL   => 0x58,
+                    Interrupt::JOYPAD   => 0x60,
+                    _ => unreachable!("Only one bit can be set.")
                 }
             },
-            (3,6,3) => Instr::DisableInterrupts,
-            (3,7,3) => Instr::EnableInterrupts,
-            (3,0...3,4) => Instr::Call(self.imm16(cursor), Cond::from(y)),
-            (3,_,5) if y % 2 == 0 => Instr::Push(Reg16::from(y, false)),
-            (3,1,5) => Instr::Call(self.imm16(cursor), Cond::Always),
-            (3,0,6) => Instr::AddImm(self.imm8(cursor)),
-            (3,1,6) => Instr::AddCarryImm(self.imm8(cursor)),
-            (3,2,6) => Instr::SubImm(self.imm8(cursor)),
-            (3,3,6) => Instr::SubCarryImm(self.imm8(cursor)),
-            (3,4,6) => Instr::AndImm(self.imm8(cursor)),
-            (3,5,6) => Instr::XorImm(self.imm8(cursor)),
-            (3,6,6) => Instr::OrImm(self.imm8(cursor)),
-            (3,7,6) => Instr::CompImm(self.imm8(cursor)),
-            (3,_,7) => Instr::Restart(y),
-            _ => Instr::Bad(opcode),
-        }
-    }
-    #[inline(always)]
-    fn imm8(&self, cursor: &mut u16) -> u8 {
-        let value = self.bus.r8(*cursor);
-        *cursor += 1;
-        value
+            None => 0x0000,
+        };
+        self.tick();
     }
     #[inline(always)]
-    fn imm16(&self, cursor: &mut u16) -> u16 {
-        let value = self.bus.r16(*cursor);
-        *cursor += 2;
-        value
+    pub fn fetch(&self, cursor: &mut u16) -> Instr {
+        let bus = &self.bus;
+        decode_from(|| {
+            let value = bus.peek(*cursor);
+            *cursor = cursor.wrapping_add(1);
+            value
+        })
     }
 
-    pub fn exec(&mut self, instr: Instr) -> u32 {
+    pub fn exec(&mut self, instr: Instr) {
         match instr {
-            Instr::Nop => 4,
+            Instr::Nop => {},
             Instr::Stop => {
+                self.bus.reset_divider();
                 self.stopped = true;
                 self.redraw = true;
-                4
             },
             Instr::StoreSP(addr) => {
-                self.bus.w16(addr, self.reg.sp);
-                20
+                let sp = self.reg.sp;
+                self.write8(addr, sp as u8);
+                self.write8(addr.wrapping_add(1), (sp >> 8) as u8);
             },
             Instr::JumpRelative(offset, cond) => {
                 if self.test_cc(cond) {
                     self.reg.pc = (self.reg.pc as i16).wrapping_add(offset as i16) as u16;
-                    12
-                } else {
-                    8
+                    self.tick();
                 }
             },
             Instr::LoadImm16(reg, imm) => {
                 self.set_reg16(reg, imm);
-                12
             },
             Instr::AddHL(reg) => {
                 let hl = self.get_reg16(Reg16::HL);
@@ -226,27 +255,27 @@ impl Cpu {
                 let half_carry = ((hl & 0x7ff) + (rr & 0x7ff)) & 0x800 != 0;
                 self.reg.set_flags_nhc(false, half_carry, carry);
                 self.set_reg16(Reg16::HL, result);
-                8
+                self.tick();
             },
             Instr::StoreA(reg) => {
                 let addr = self.get_indirect(reg);
-                self.bus.w8(addr, self.reg.r8(Reg8::A));
-                8
+                let a = self.reg.r8(Reg8::A);
+                self.write8(addr, a);
             },
             Instr::LoadA(reg) => {
                 let addr = self.get_indirect(reg);
-                self.reg.w8(Reg8::A, self.bus.r8(addr));
-                8
+                let value = self.read8(addr);
+                self.reg.w8(Reg8::A, value);
             },
             Instr::Inc16(reg) => {
                 let result = self.get_reg16(reg).wrapping_add(1);
                 self.set_reg16(reg, result);
-                8
+                self.tick();
             },
             Instr::Dec16(reg) => {
                 let result = self.get_reg16(reg).wrapping_sub(1);
                 self.set_reg16(reg, result);
-                8
+                self.tick();
             },
             Instr::Inc8(reg) => {
                 let value = self.get_reg8(reg);
@@ -255,7 +284,6 @@ impl Cpu {
                 self.reg.f_z = result == 0;
                 self.reg.f_n = false;
                 self.reg.f_h = value & 0xf == 0xf;
-                if reg == Reg8::HL { 12 } else { 4 }
             },
             Instr::Dec8(reg) => {
                 let value = self.get_reg8(reg);
@@ -264,31 +292,25 @@ impl Cpu {
                 self.reg.f_z = result == 0;
                 self.reg.f_n = true;
                 self.reg.f_h = value & 0xf == 0;
-                if reg == Reg8::HL { 12 } else { 4 }
             },
             Instr::LoadImm8(reg, imm) => {
                 self.set_reg8(reg, imm);
-                if reg == Reg8::HL { 12 } else { 8 }
             },
             Instr::RotateALeft => {
                 self.rotate(Reg8::A, |a,_c| (a.rotate_left(1), a & 0x80 != 0));
                 self.reg.f_z = false;
-                4
             },
             Instr::RotateALeftCarry => {
                 self.rotate(Reg8::A, |a,c| (a << 1 | if c { 1 } else { 0 }, a & 0x80 != 0));
                 self.reg.f_z = false;
-                4
             },
             Instr::RotateARight => {
                 self.rotate(Reg8::A, |a,_c| (a.rotate_right(1), a & 0x01 != 0));
                 self.reg.f_z = false;
-                4
             },
             Instr::RotateARightCarry => {
                 self.rotate(Reg8::A, |a,c| (a >> 1 | if c { 0x80 } else { 0 }, a & 0x1 != 0));
                 self.reg.f_z = false;
-                4
             },
             Instr::DecimalAdjust => {
                 let a = self.reg.r8(Reg8::A);
@@ -306,32 +328,30 @@ impl Cpu {
                 self.reg.f_z = result == 0;
                 self.reg.f_h = false;
                 self.reg.w8(Reg8::A, result);
-                4
             },
             Instr::Complement => {
                 let complemented = !self.reg.r8(Reg8::A);
                 self.reg.w8(Reg8::A, complemented);
                 self.reg.f_n = true;
                 self.reg.f_h = true;
-                4
             },
             Instr::ComplementCarry => {
                 let complemented = !self.reg.f_c;
                 self.reg.set_flags_nhc(false, false, complemented);
-                4
             },
             Instr::SetCarry => {
                 self.reg.set_flags_nhc(false, false, true);
-                4
             },
             Instr::Halt => {
-                self.halted = true;
-                4
+                if !self.master_interrupt_flag && self.bus.get_highest_priority_interrupt().is_some() {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
             },
             Instr::RegCopy(to,from) => {
                 let val = self.get_reg8(from);
                 self.set_reg8(to, val);
-                if to == Reg8::HL || from == Reg8::HL { 8 } else { 4 }
             },
             Instr::Add(reg) | Instr::AddCarry(reg) => {
                 let a = self.reg.r8(Reg8::A);
@@ -339,7 +359,6 @@ impl Cpu {
                 let carry_in = match instr { Instr::AddCarry(_) => self.reg.f_c, _ => false };
                 let result = self.add8(a, operand, carry_in);
                 self.reg.w8(Reg8::A, result);
-                if reg == Reg8::HL { 8 } else { 4 }
             },
             Instr::AddImm(imm) | Instr::AddCarryImm(imm) => {
                 let a = self.reg.r8(Reg8::A);
@@ -349,7 +368,6 @@ impl Cpu {
                 };
                 let result = self.add8(a, imm, carry_in);
                 self.reg.w8(Reg8::A, result);
-                4
             },
             Instr::Sub(reg) | Instr::SubCarry(reg) | Instr::Comp(reg) => {
                 let a = self.reg.r8(Reg8::A);
@@ -364,7 +382,6 @@ impl Cpu {
                 if write_back {
                     self.reg.w8(Reg8::A, result);
                 }
-                if reg == Reg8::HL { 8 } else { 4 }
             },
             Instr::SubImm(imm) | Instr::SubCarryImm(imm) | Instr::CompImm(imm) => {
                 let a = self.reg.r8(Reg8::A);
@@ -378,201 +395,220 @@ impl Cpu {
                 if write_back {
                     self.reg.w8(Reg8::A, result);
                 }
-                4
             },
             Instr::And(reg) => {
                 let value = self.get_reg8(reg);
                 self.logical(value, |a,b| a & b, true);
-                if reg == Reg8::HL { 8 } else { 4 }
             },
             Instr::Or(reg) => {
                 let value = self.get_reg8(reg);
                 self.logical(value, |a,b| a | b, false);
-                if reg == Reg8::HL { 8 } else { 4 }
             },
             Instr::Xor(reg) => {
                 let value = self.get_reg8(reg);
                 self.logical(value, |a,b| a ^ b, false);
-                if reg == Reg8::HL { 8 } else { 4 }
             },
             Instr::AndImm(imm) => {
                 self.logical(imm, |a,b| a & b, true);
-                4
             },
             Instr::OrImm(imm) => {
                 self.logical(imm, |a,b| a | b, false);
-                4
             },
             Instr::XorImm(imm) => {
                 self.logical(imm, |a,b| a ^ b, false);
-                4
+            },
+            Instr::Ret(Cond::Always) => {
+                self.ret();
             },
             Instr::Ret(cond) => {
+                self.tick();
                 if self.test_cc(cond) {
                     self.ret();
-                    20
-                } else {
-                    8
                 }
             },
             Instr::RetI => {
                 self.ret();
                 self.master_interrupt_flag = true;
-                16
             },
             Instr::StoreIO(offset) => {
                 let addr = 0xff00 + offset as u16;
-                self.bus.w8(addr, self.reg.r8(Reg8::A));
-                12
+                let a = self.reg.r8(Reg8::A);
+                self.write8(addr, a);
             },
             Instr::LoadIO(offset) => {
                 let addr = 0xff00 + offset as u16;
-                self.reg.w8(Reg8::A, self.bus.r8(addr));
-                12
+                let value = self.read8(addr);
+                self.reg.w8(Reg8::A, value);
             },
             Instr::StackAdjust(disp) => {
                 let sp = self.reg.sp;
                 self.reg.sp = self.disp16(sp, disp);
-                16
+                self.tick();
+                self.tick();
             },
             Instr::LoadLocalAddr(disp) => {
                 let sp = self.reg.sp;
                 let result = self.disp16(sp, disp);
                 self.reg.w16(Reg16::HL, result);
-                12
+                self.tick();
             },
             Instr::Pop(reg) => {
-                let value = self.bus.r16(self.reg.sp);
-                self.reg.sp += 2;
+                let value = self.pop16();
                 self.reg.w16(reg, value);
-                12
             },
             Instr::Push(reg) => {
-                self.reg.sp -= 2;
-                self.bus.w16(self.reg.sp, self.reg.r16(reg));
-                12
+                let value = self.reg.r16(reg);
+                self.tick();
+                self.push16(value);
             },
             Instr::Jump(dest, cond) => {
                 if self.test_cc(cond) {
                     self.reg.pc = dest;
-                    16
-                } else {
-                    12
+                    self.tick();
                 }
             },
             Instr::JumpHL => {
                 let hl = self.reg.r16(Reg16::HL);
                 self.reg.pc = hl;
-                4
             },
             Instr::LoadStackHL => {
                 let hl = self.reg.r16(Reg16::HL);
                 self.reg.sp = hl;
-                4
+                self.tick();
             },
             Instr::StoreIOC => {
                 let addr = 0xff00 + self.reg.r8(Reg8::C) as u16;
-                self.bus.w8(addr, self.reg.r8(Reg8::A));
-                12
+                let a = self.reg.r8(Reg8::A);
+                self.write8(addr, a);
             },
             Instr::LoadIOC => {
                 let addr = 0xff00 + self.reg.r8(Reg8::C) as u16;
-                self.reg.w8(Reg8::A, self.bus.r8(addr));
-                12
+                let value = self.read8(addr);
+                self.reg.w8(Reg8::A, value);
             },
             Instr::StoreGlobal(addr) => {
-                self.bus.w8(addr, self.reg.r8(Reg8::A));
-                16
+                let a = self.reg.r8(Reg8::A);
+                self.write8(addr, a);
             },
             Instr::LoadGlobal(addr) => {
-                self.reg.w8(Reg8::A, self.bus.r8(addr));
-                16
+                let value = self.read8(addr);
+                self.reg.w8(Reg8::A, value);
             },
             Instr::DisableInterrupts => {
-                self.interrupts_buffer = Some(false);
-                4
+                self.master_interrupt_flag = false;
+                self.interrupts_delay = 0;
             },
             Instr::EnableInterrupts => {
-                self.interrupts_buffer = Some(true);
-                4
+                if !self.master_interrupt_flag && self.interrupts_delay == 0 {
+                    self.interrupts_delay = 2;
+                }
             },
             Instr::Call(dest, cond) => {
                 if self.test_cc(cond) {
                     self.call(dest);
-                    24
-                } else {
-                    12
                 }
             },
             Instr::Restart(index) => {
                 self.call(index as u16 * 8);
-                16
             },
             Instr::Bit(reg, bit) => {
                 let value = self.get_reg8(reg);
                 self.reg.f_z = value & (1 << bit) == 0;
                 self.reg.f_n = false;
                 self.reg.f_h = true;
-                if reg == Reg8::HL { 12 } else { 8 }
             },
             Instr::Set(reg, bit) => {
                 let value = self.get_reg8(reg);
                 let result = value | (1 << bit);
                 self.set_reg8(reg, result);
-                if reg == Reg8::HL { 16 } else { 8 }
             },
             Instr::Reset(reg, bit) => {
                 let value = self.get_reg8(reg);
                 let result = value & !(1 << bit);
                 self.set_reg8(reg, result);
-                if reg == Reg8::HL { 16 } else { 8 }
             },
             Instr::RotateLeft(reg) => {
                 self.rotate(reg, |a,_c| (a.rotate_left(1), a & 0x80 != 0));
-                if reg == Reg8::HL { 16 } else { 8 }
             },
             Instr::RotateRight(reg) => {
                 self.rotate(reg, |a,_c| (a.rotate_right(1), a & 0x01 != 0));
-                if reg == Reg8::HL { 16 } else { 8 }
             },
             Instr::RotateLeftCarry(reg) => {
                 self.rotate(reg, |a,c| (a << 1 | if c { 1 } else { 0 }, a & 0x80 != 0));
-                if reg == Reg8::HL { 16 } else { 8 }
             },
             Instr::RotateRightCarry(reg) => {
                 self.rotate(reg, |a,c| (a >> 1 | if c { 0x80 } else { 0 }, a & 1 != 0));
-                if reg == Reg8::HL { 16 } else { 8 }
             },
             Instr::ShiftLeft(reg) => {
                 self.rotate(reg, |a,_c| (a << 1, a & 0x80 != 0));
-                if reg == Reg8::HL { 16 } else { 8 }
             },
             Instr::ShiftRightLogical(reg) => {
                 self.rotate(reg, |a,_c| (a >> 1, a & 1 != 0));
-                if reg == Reg8::HL { 16 } else { 8 }
             },
             Instr::ShiftRightArith(reg) => {
                 self.rotate(reg, |a,_c| (((a as i8) >> 1) as u8, a & 1 != 0));
-                if reg == Reg8::HL { 16 } else { 8 }
             },
             Instr::SwapBytes(reg) => {
                 self.rotate(reg, |a,_c| (a.rotate_left(4), false));
-                if reg == Reg8::HL { 16 } else { 8 }
             }
             Instr::Bad(opcode) => unimplemented!("Unimplemented opcode: {:X} \nregs: {:?}", opcode, self.reg)
         }
     }
+    // Advance the rest of the system by one machine cycle.
+    #[inline(always)]
+    fn tick(&mut self) {
+        if self.bus.tick() {
+            self.redraw = true;
+            self.frames += 1;
+        }
+        self.cycles += 4;
+    }
+    #[inline(always)]
+    fn read8(&mut self, addr: u16) -> u8 {
+        let value = self.fetch8(addr);
+        if self.watching {
+            self.accesses.push(Access::Read { addr, value });
+        }
+        value
+    }
+    // Reads an instruction byte, which watchpoints don't see.
+    #[inline(always)]
+    fn fetch8(&mut self, addr: u16) -> u8 {
+        self.tick();
+        if self.bus.warn_unmapped(addr) {
+            eprintln!("{:04x}: read from unmapped address {:04x}", self.instr_pc, addr);
+        }
+        self.bus.read(addr)
+    }
     #[inline(always)]
-    fn get_reg8(&self, reg: Reg8) -> u8 {
+    fn write8(&mut self, addr: u16, value: u8) {
+        self.tick();
+        if self.bus.warn_unmapped(addr) {
+            eprintln!("{:04x}: write {:02x} to unmapped address {:04x}", self.instr_pc, value, addr);
+        }
+        if self.watching {
+            let old = self.bus.peek(addr);
+            self.accesses.push(Access::Write { addr, old, new: value });
+        }
+        self.bus.write(addr, value);
+    }
+    #[inline(always)]
+    fn get_reg8(&mut self, reg: Reg8) -> u8 {
         match reg {
-            Reg8::HL => self.bus.r8(self.reg.r16(Reg16::HL)),
+            Reg8::HL => {
+                let hl = self.reg.r16(Reg16::HL);
+                self.read8(hl)
+            },
             _ => self.reg.r8(reg)
         }
     }
     #[inline(always)]
     fn set_reg8(&mut self, reg: Reg8, value: u8) {
         match reg {
-            Reg8::HL => self.bus.w8(self.reg.r16(Reg16::HL), value),
+            Reg8::HL => {
+                let hl = self.reg.r16(Reg16::HL);
+                self.write8(hl, value)
+            },
             _ => self.reg.w8(reg, value)
         }
     }
@@ -661,52 +697,101 @@ impl Cpu {
         self.reg.w8(Reg8::A, result);
     }
     #[inline(always)]
+    fn push16(&mut self, value: u16) {
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        let sp = self.reg.sp;
+        self.write8(sp, (value >> 8) as u8);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        let sp = self.reg.sp;
+        self.write8(sp, value as u8);
+    }
+    #[inline(always)]
+    fn pop16(&mut self) -> u16 {
+        let sp = self.reg.sp;
+        let lo = self.read8(sp) as u16;
+        let hi = self.read8(sp.wrapping_add(1)) as u16;
+        self.reg.sp = sp.wrapping_add(2);
+        hi << 8 | lo
+    }
+    #[inline(always)]
     fn ret(&mut self) {
-        let addr = self.bus.r16(self.reg.sp);
-        self.reg.sp += 2;
-        self.reg.pc = addr;
+        self.reg.pc = self.pop16();
+        self.tick();
     }
     #[inline(always)]
     fn call(&mut self, dest: u16) {
-        self.reg.sp -= 2;
-        self.bus.w16(self.reg.sp, self.reg.pc);
+        let pc = self.reg.pc;
+        self.tick();
+        self.push16(pc);
         self.reg.pc = dest;
     }
 
-    pub fn key_down(&mut self, button: Button) {
-        if self.stopped {
-            self.stopped = false;
-            // TODO: check input line?
-        }
-        self.bus.key_down(button);
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.trace = trace;
     }
-    pub fn key_up(&mut self, button: Button) {
-        self.bus.key_up(button);
+    // An EI that hasn't taken effect yet counts as enabled.
+    pub fn interrupts_enabled(&self) -> bool {
+        self.master_interrupt_flag || self.interrupts_delay > 0
     }
-    pub fn update(&mut self, cycles: u32) {
-        self.redraw = self.bus.update(cycles);
+    // Whether an EI is waiting for the next instruction.
+    pub fn interrupts_pending_enable(&self) -> bool {
+        self.interrupts_delay > 0
     }
-    pub fn fill_screen_buffer(&self, buffer: &mut[u8; SCREEN_BUFFER_SIZE]) {
-        if self.stopped {
-            *buffer = [0xff; SCREEN_BUFFER_SIZE];
-        } else {
-            self.bus.fill_screen_buffer(buffer);
-        }
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+    pub fn set_interrupts_enabled(&mut self, value: bool) {
+        self.master_interrupt_flag = value;
+        self.interrupts_delay = 0;
     }
     pub fn needs_redraw(&mut self) -> bool {
         let result = self.redraw;
         self.redraw = false;
         result
     }
-    fn update_debugger(&mut self, debug: &mut DebugState) {
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+    // Tracks calls by where their return addresses are on the stack, so
+    // anything that moves SP past one (RET, POP, LD SP,HL...) ends it.
+    fn update_call_stack(&mut self, flow: Option<Flow>, interrupted: bool) {
+        let sp = self.reg.sp;
+        while self.call_stack.last().is_some_and(|frame| frame.sp < sp) {
+            self.call_stack.pop();
+        }
+        if flow == Some(Flow::Call) {
+            let return_addr = (self.bus.peek(sp.wrapping_add(1)) as u16) << 8 | self.bus.peek(sp) as u16;
+            if self.call_stack.len() == MAX_CALL_DEPTH {
+                self.call_stack.remove(0);
+            }
+            self.call_stack.push(CallFrame {
+                call_site: self.instr_pc,
+                target: self.reg.pc,
+                return_addr,
+                return_bank: self.bus.rom_bank(return_addr),
+                sp,
+                interrupt: interrupted,
+            });
+        }
+    }
+    fn update_debugger(&mut self, debug: &mut DebugState, new_frame: bool) {
         if debug.steps_remaining > 0 {
             debug.steps_remaining -= 1;
             if debug.steps_remaining == 0 { debug.paused = true; }
         }
-        if debug.breakpoints.contains(&self.reg.pc) {
+        if debug.run_to.is_some() && debug.check_run_to(self.reg.pc, self.reg.sp, new_frame) {
+            debug.paused = true;
+        }
+        if !debug.breakpoints.is_empty() && debug.check_breakpoints(&self.reg, &self.bus) {
             debug.paused = true;
             debug.steps_remaining = 0;
         }
-        self.bus.check_watch_buffer(debug);
+        if !self.accesses.is_empty() {
+            debug.check_watchpoints(&self.accesses, self.instr_pc, &self.bus);
+            self.accesses.clear();
+        }
     }
 }
@@ -1,10 +1,21 @@
 use rustyline::Editor;
-use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self,Read,Write};
+use std::path::Path;
 use std::str::FromStr;
 
+use assembler;
+use disasm;
+use bus::MemoryBus;
+use cartridge::MemoryController;
+use cpu::Interrupt;
 use cpu::Cpu;
+use expr::Expr;
+use instructions::{Instr,decode_from};
+use registers::{Registers,Reg8,Reg16};
+use symbols::Symbols;
 
-type CommandFn = fn(&Cpu, &mut Debugger, &Vec<&str>);
+type CommandFn = fn(&mut Cpu, &mut Debugger, &[&str]);
 
 #[derive(Clone,Copy)]
 struct Command {
@@ -12,20 +23,197 @@ struct Command {
     func: CommandFn
 }
 
+pub struct Breakpoint {
+    pub id: u32,
+    // Only stop when this bank is mapped at `addr`.
+    pub bank: Option<usize>,
+    pub addr: u16,
+    // The condition as it was typed, and parsed.
+    pub condition: Option<(String, Expr)>,
+    pub hits: u32,
+    // How many more hits to let through without stopping.
+    pub ignore: u32,
+}
+
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum WatchKind {
+    Read, Write, Access
+}
+
+pub struct Watchpoint {
+    pub id: u32,
+    pub bank: Option<usize>,
+    // Inclusive.
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+    // Only stop when this value is read or written.
+    pub value: Option<u8>,
+    pub hits: u32,
+}
+
+// A memory access made by an instruction, for checking watchpoints.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum Access {
+    Read { addr: u16, value: u8 },
+    Write { addr: u16, old: u8, new: u8 },
+}
+
+// A call or return made by an instruction or interrupt.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum Flow {
+    Call, Return
+}
+
+// Where to stop for next, finish, until and frame.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum RunTo {
+    // An address, reached with SP back at or above the given SP, so
+    // that calls made on the way have returned however they did it.
+    Next(u16, u16),
+    // The return from the current function, once SP is above the
+    // given slot holding its return address.
+    Finish(u16),
+    // An address, at any depth.
+    Until(u16),
+    // The start of the next frame.
+    Frame,
+}
+
 pub struct DebugState {
     pub steps_remaining: u32,
-    pub breakpoints: HashSet<u16>,
-    pub watchpoints: HashSet<u16>,
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
     pub paused: bool,
+    // Why execution stopped, for the debugger to report.
+    pub stop_reason: Option<String>,
+    pub run_to: Option<RunTo>,
+    // Breakpoints and watchpoints are numbered together.
+    next_id: u32,
+}
+
+impl Default for DebugState {
+    fn default() -> Self { DebugState::new() }
 }
 
 impl DebugState {
     pub fn new() -> Self {
         DebugState {
             steps_remaining: 0,
-            breakpoints: HashSet::new(),
-            watchpoints: HashSet::new(),
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
             paused: false,
+            stop_reason: None,
+            run_to: None,
+            next_id: 1,
+        }
+    }
+    pub fn set_run_to(&mut self, run_to: RunTo) {
+        self.run_to = Some(run_to);
+    }
+    // Whether the instruction just run reached `run_to`.
+    pub fn check_run_to(&mut self, pc: u16, sp: u16, new_frame: bool) -> bool {
+        let done = match self.run_to {
+            Some(RunTo::Next(addr, frame_sp)) => pc == addr && sp >= frame_sp,
+            Some(RunTo::Finish(frame_sp)) => sp > frame_sp,
+            Some(RunTo::Until(addr)) => pc == addr,
+            Some(RunTo::Frame) => new_frame,
+            None => false,
+        };
+        if done {
+            self.run_to = None;
+        }
+        done
+    }
+    pub fn add_breakpoint(&mut self, bank: Option<usize>, addr: u16, condition: Option<(String, Expr)>) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id,
+            bank,
+            addr,
+            condition,
+            hits: 0,
+            ignore: 0,
+        });
+        id
+    }
+    // Whether a breakpoint stops execution at the current PC. A
+    // condition that can't be evaluated stops so it can be fixed.
+    pub fn check_breakpoints<B: MemoryBus>(&mut self, reg: &Registers, bus: &B) -> bool {
+        let mut stop = false;
+        let bank = bus.bank(reg.pc);
+        for bp in self.breakpoints.iter_mut().filter(|bp| bp.addr == reg.pc && bp.bank.unwrap_or(bank) == bank) {
+            if let Some((_, ref condition)) = bp.condition {
+                match condition.eval(reg, bus) {
+                    Ok(0) => continue,
+                    Ok(_) => {},
+                    Err(e) => {
+                        self.stop_reason = Some(format!("Breakpoint {}: {}", bp.id, e));
+                        stop = true;
+                        continue;
+                    }
+                }
+            }
+            bp.hits += 1;
+            if bp.ignore > 0 {
+                bp.ignore -= 1;
+                continue;
+            }
+            self.stop_reason = Some(format!("Breakpoint {} at {}, hit {} time{}",
+                                            bp.id, location(bp.bank, bp.addr), bp.hits, if bp.hits == 1 { "" } else { "s" }));
+            stop = true;
+        }
+        stop
+    }
+    pub fn add_watchpoint(&mut self, bank: Option<usize>, start: u16, end: u16, kind: WatchKind, value: Option<u8>) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watchpoints.push(Watchpoint {
+            id,
+            bank,
+            start,
+            end,
+            kind,
+            value,
+            hits: 0,
+        });
+        id
+    }
+    // Pauses if any of the accesses made by the instruction at `pc`
+    // trigger a watchpoint. Banks are checked after the instruction,
+    // which is only different if it switched the bank it accessed.
+    pub fn check_watchpoints<B: MemoryBus>(&mut self, accesses: &[Access], pc: u16, bus: &B) {
+        for access in accesses {
+            let (addr, value, write) = match *access {
+                Access::Read { addr, value } => (addr, value, false),
+                Access::Write { addr, new, .. } => (addr, new, true),
+            };
+            let bank = bus.bank(addr);
+            for wp in self.watchpoints.iter_mut() {
+                let kind_matches = match wp.kind {
+                    WatchKind::Read => !write,
+                    WatchKind::Write => write,
+                    WatchKind::Access => true,
+                };
+                if !kind_matches || addr < wp.start || addr > wp.end ||
+                    wp.bank.unwrap_or(bank) != bank || wp.value.is_some_and(|v| v != value) {
+                    continue;
+                }
+                wp.hits += 1;
+                let detail = match *access {
+                    Access::Read { value, .. } => format!("read {:02x} from {:04x}", value, addr),
+                    Access::Write { old, new, .. } => format!("write to {:04x}: {:02x} -> {:02x}", addr, old, new),
+                };
+                let reason = format!("Watchpoint {}: {} by instruction at {:04x}", wp.id, detail, pc);
+                // An instruction can trigger several watchpoints.
+                self.stop_reason = Some(match self.stop_reason.take() {
+                    Some(earlier) => earlier + "\n" + &reason,
+                    None => reason,
+                });
+                self.paused = true;
+                self.steps_remaining = 0;
+            }
         }
     }
 }
@@ -35,6 +223,7 @@ pub struct Debugger {
     readline: Editor<()>,
     execute: bool,
     state: DebugState,
+    symbols: Symbols,
 }
 
 impl Debugger {
@@ -43,7 +232,8 @@ impl Debugger {
             commands: Vec::new(),
             readline: Editor::new(),
             execute: false,
-            state: DebugState::new()
+            state: DebugState::new(),
+            symbols: Symbols::new(),
         };
         result.register_command("continue", cmd_continue);
         result.register_command("registers", cmd_registers);
@@ -54,10 +244,34 @@ impl Debugger {
         result.register_command("xamine", cmd_examine);
         result.register_command("list", cmd_list);
         result.register_command("step", cmd_step);
+        result.register_command("assemble", cmd_assemble);
+        result.register_command("ignore", cmd_ignore);
+        result.register_command("print", cmd_print);
+        result.register_command("set", cmd_set);
+        result.register_command("write", cmd_write);
+        result.register_command("fill", cmd_fill);
+        result.register_command("flag", cmd_flag);
+        result.register_command("next", cmd_next);
+        result.register_command("finish", cmd_finish);
+        result.register_command("until", cmd_until);
+        result.register_command("frame", cmd_frame);
+        result.register_command("backtrace", cmd_backtrace);
+        result.register_command("dump", cmd_dump);
+        result.register_command("load", cmd_load);
+        result.register_command("lcd", cmd_lcd);
+        result.register_command("timer", cmd_timer);
+        result.register_command("int", cmd_interrupts);
+        result.register_command("dma", cmd_dma);
+        result.register_command("cart", cmd_cartridge);
         result
     }
-    pub fn debug(&mut self, cpu: &Cpu) {
-        print_instr(cpu, cpu.reg.pc);
+    pub fn debug(&mut self, cpu: &mut Cpu) {
+        if let Some(reason) = self.state.stop_reason.take() {
+            println!("{}", reason);
+        }
+        // Stopping for any reason cancels next, finish and so on.
+        self.state.run_to = None;
+        print_instr(cpu, &self.symbols, None, cpu.reg.pc);
 
         self.state.paused = false;
         self.execute = false;
@@ -69,10 +283,16 @@ impl Debugger {
             self.readline.add_history_entry(&line);
             let mut words = line.split_whitespace();
             if let Some(command) = words.next() {
-                let args: Vec<&str> = words.collect();
+                let mut args: Vec<&str> = words.collect();
+                // A count after a slash, as in x/16, is passed on as
+                // the first argument.
+                let command = match command.find('/') {
+                    Some(i) => { args.insert(0, &command[i..]); &command[..i] },
+                    None => command,
+                };
                 match self.lookup_command(command) {
                     Ok(cmd) => (cmd.func)(cpu, self, &args),
-                    Err(_) => {}
+                    Err(e) => println!("{}", e),
                 }
             }
         }
@@ -88,76 +308,230 @@ impl Debugger {
     pub fn get_state(&mut self) -> &mut DebugState {
         &mut self.state
     }
+    // Loads labels from a .sym file, returning how many there were.
+    pub fn load_symbols(&mut self, path: &Path) -> io::Result<usize> {
+        self.symbols = Symbols::load(path)?;
+        Ok(self.symbols.len())
+    }
+    // A label, or a hex address.
+    fn parse_addr(&self, text: &str) -> Option<u16> {
+        self.symbols.lookup(text).map(|(_, addr)| addr)
+            .or_else(|| u16::from_str_radix(text, 16).ok())
+    }
+    // An address with an optional bank, as `bank:addr` or a label in
+    // switchable ROM. Without a bank it means whatever is mapped.
+    fn parse_location(&self, text: &str) -> Option<(Option<usize>, u16)> {
+        if let Some((bank, addr)) = text.split_once(':') {
+            let bank = usize::from_str_radix(bank, 16).ok()?;
+            return self.parse_addr(addr).map(|addr| (Some(bank), addr));
+        }
+        match self.symbols.lookup(text) {
+            Some((bank, addr @ 0x4000 ..= 0x7fff)) => Some((Some(bank), addr)),
+            Some((_, addr)) => Some((None, addr)),
+            None => u16::from_str_radix(text, 16).ok().map(|addr| (None, addr)),
+        }
+    }
+    fn parse_expr(&self, text: &str) -> Result<Expr, String> {
+        Expr::parse_with_symbols(text, Some(&self.symbols))
+    }
     fn register_command(&mut self, name: &'static str, func: CommandFn) {
-        self.commands.push(Command { name: name, func: func });
+        self.commands.push(Command { name, func });
     }
-    fn lookup_command(&self, command: &str) -> Result<Command,()> {
-        let mut candidates: Vec<Command> = Vec::new();
-        for c in self.commands.iter() {
-            if c.name.starts_with(command) {
-                candidates.push(*c);
-            }
+    // An exact name or an alias wins, otherwise an abbreviation has to
+    // match just one command.
+    fn lookup_command(&self, command: &str) -> Result<Command, String> {
+        let command = ALIASES.iter().find(|&&(alias, _)| alias == command)
+            .map_or(command, |&(_, name)| name);
+        if let Some(c) = self.commands.iter().find(|c| c.name == command) {
+            return Ok(*c);
         }
+        let candidates: Vec<&Command> = self.commands.iter()
+            .filter(|c| c.name.starts_with(command)).collect();
         match candidates.len() {
-            0 => println!("Unknown command: {}", command),
-            1 => return Ok(candidates[0]),
+            0 => Err(format!("Unknown command: {}", command)),
+            1 => Ok(*candidates[0]),
             _ => {
-                print!("Did you mean:");
-                for c in candidates { print!(" {}", c.name); }
-                println!("");
+                let names: Vec<&str> = candidates.iter().map(|c| c.name).collect();
+                Err(format!("Did you mean: {}", names.join(" ")))
             }
         }
-        Err(())
+    }
+    // The full name of a possibly abbreviated command.
+    pub fn command_name(&self, command: &str) -> Result<&'static str, String> {
+        self.lookup_command(command).map(|c| c.name)
     }
 }
-fn print_instr(cpu: &Cpu, mut addr: u16) -> u16 {
-    print!("{:04x}: ", addr);
-    println!("{}", cpu.fetch(&mut addr));
+// Prints the instruction at `addr` in `bank`, or in whatever is mapped,
+// under its label if it has one, and returns the address of the next
+// instruction.
+fn print_instr(cpu: &Cpu, symbols: &Symbols, bank: Option<usize>, mut addr: u16) -> u16 {
+    let shown = bank.unwrap_or_else(|| cpu.bus.bank(addr));
+    if let Some(label) = symbols.label(shown, addr) {
+        println!("{}:", label);
+    }
+    print!("{:02x}:{:04x}: ", shown, addr);
+    let instr = decode_from(|| {
+        let value = match bank {
+            Some(bank) => cpu.bus.peek_bank(bank, addr),
+            None => cpu.bus.peek(addr),
+        };
+        addr = addr.wrapping_add(1);
+        value
+    });
+    // Branches within switchable ROM stay in the same bank.
+    let target_bank = |target: u16| match target {
+        0x4000 ..= 0x7fff if (0x4000..0x8000).contains(&addr) => shown,
+        _ => cpu.bus.rom_bank(target),
+    };
+    match disasm::branch_target(&instr, addr).and_then(|target| symbols.describe(target_bank(target), target)) {
+        Some(name) => println!("{} <{}>", instr, name),
+        None => println!("{}", instr),
+    }
     addr
 }
+// An address, with its bank if it has one.
+fn location(bank: Option<usize>, addr: u16) -> String {
+    match bank {
+        Some(bank) => format!("{:02x}:{:04x}", bank, addr),
+        None => format!("{:04x}", addr),
+    }
+}
+// An address, followed by its label if it has one.
+fn describe(symbols: &Symbols, bank: usize, addr: u16) -> String {
+    match symbols.describe(bank, addr) {
+        Some(name) => format!("{:04x} <{}>", addr, name),
+        None => format!("{:04x}", addr),
+    }
+}
 
-fn cmd_continue(_cpu: &Cpu, dbg: &mut Debugger, _args: &Vec<&str>) {
+fn cmd_continue(_cpu: &mut Cpu, dbg: &mut Debugger, _args: &[&str]) {
     dbg.execute = true;
 }
-fn cmd_registers(cpu: &Cpu, _dbg: &mut Debugger, _args: &Vec<&str>) {
+fn cmd_registers(cpu: &mut Cpu, _dbg: &mut Debugger, _args: &[&str]) {
     println!(" A F   B C   D E   H L    PC SP\n{}", cpu.reg);
 }
-fn cmd_breakpoint(_cpu: &Cpu, dbg: &mut Debugger, args: &Vec<&str>) {
-    if args.len() != 1 {
-        println!("Usage: breakpoint <addr>");
+fn cmd_breakpoint(_cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    if args.is_empty() {
+        for bp in dbg.state.breakpoints.iter() {
+            print!("{}: {}", bp.id, location(bp.bank, bp.addr));
+            if let Some((ref text, _)) = bp.condition {
+                print!(" if {}", text);
+            }
+            print!(", hit {} times", bp.hits);
+            if bp.ignore > 0 {
+                print!(", ignoring the next {}", bp.ignore);
+            }
+            println!();
+        }
         return;
     }
-    if let Ok(addr) = u16::from_str_radix(args[0], 16) {
-        dbg.state.breakpoints.insert(addr);
+    let (bank, addr) = match dbg.parse_location(args[0]) {
+        Some(location) => location,
+        None => { println!("Usage: breakpoint [[<bank>:]<addr> [if <condition>]]"); return; }
+    };
+    let condition = match args.get(1) {
+        None => None,
+        Some(&"if") if args.len() > 2 => {
+            let text = args[2..].join(" ");
+            match dbg.parse_expr(&text) {
+                Ok(expr) => Some((text, expr)),
+                Err(e) => { println!("{}", e); return; }
+            }
+        },
+        Some(_) => { println!("Usage: breakpoint [[<bank>:]<addr> [if <condition>]]"); return; }
+    };
+    let id = dbg.state.add_breakpoint(bank, addr, condition);
+    println!("Breakpoint {} at {}", id, location(bank, addr));
+}
+fn cmd_ignore(_cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    let (id, count) = match (args.first().map(|a| u32::from_str(a)), args.get(1).map(|a| u32::from_str(a))) {
+        (Some(Ok(id)), Some(Ok(count))) if args.len() == 2 => (id, count),
+        _ => { println!("Usage: ignore <breakpoint> <count>"); return; }
+    };
+    match dbg.state.breakpoints.iter_mut().find(|bp| bp.id == id) {
+        Some(bp) => bp.ignore = count,
+        None => println!("No breakpoint {}", id),
     }
 }
-fn cmd_watchpoint(_cpu: &Cpu, dbg: &mut Debugger, args: &Vec<&str>) {
-    if args.len() != 1 {
-        println!("Usage: watchpoint <addr>");
+fn cmd_print(cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    if args.is_empty() {
+        println!("Usage: print <expression>");
         return;
     }
-    if let Ok(addr) = u16::from_str_radix(args[0], 16) {
-        dbg.state.watchpoints.insert(addr);
+    match dbg.parse_expr(&args.join(" ")).and_then(|expr| expr.eval(&cpu.reg, &cpu.bus)) {
+        Ok(value) => println!("{} ({:#x})", value, value),
+        Err(e) => println!("{}", e),
+    }
+}
+fn cmd_watchpoint(_cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    const USAGE: &str = "Usage: watchpoint [[<bank>:]<addr>[-<end>] [read|write|access] [<value>]]";
+    if args.is_empty() {
+        for wp in dbg.state.watchpoints.iter() {
+            print!("{}: {}", wp.id, location(wp.bank, wp.start));
+            if wp.end != wp.start {
+                print!("-{:04x}", wp.end);
+            }
+            print!(" {:?}", wp.kind);
+            if let Some(value) = wp.value {
+                print!(" {:02x}", value);
+            }
+            println!(", hit {} times", wp.hits);
+        }
+        return;
     }
+    // The end can leave out the bank.
+    let mut range = args[0].splitn(2, '-').map(|addr| dbg.parse_location(addr));
+    let (bank, start, end) = match (range.next(), range.next()) {
+        (Some(Some((bank, start))), None) => (bank, start, start),
+        (Some(Some((bank, start))), Some(Some((end_bank, end))))
+            if end >= start && (end_bank.is_none() || end_bank == bank) => (bank, start, end),
+        _ => { println!("{}", USAGE); return; }
+    };
+    let (kind, rest) = match args.get(1) {
+        Some(&"read") => (WatchKind::Read, &args[2..]),
+        Some(&"write") => (WatchKind::Write, &args[2..]),
+        Some(&"access") => (WatchKind::Access, &args[2..]),
+        _ => (WatchKind::Write, &args[1..]),
+    };
+    let value = match rest {
+        [] => None,
+        [value] => match u8::from_str_radix(value, 16) {
+            Ok(value) => Some(value),
+            Err(_) => { println!("{}", USAGE); return; }
+        },
+        _ => { println!("{}", USAGE); return; }
+    };
+    let id = dbg.state.add_watchpoint(bank, start, end, kind, value);
+    println!("Watchpoint {} at {}", id, args[0]);
 }
-fn cmd_delete(_cpu: &Cpu, dbg: &mut Debugger, _args: &Vec<&str>) {
-    dbg.state.breakpoints.clear();
+fn cmd_delete(_cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    match args.first().map(|arg| u32::from_str(arg)) {
+        None => {
+            dbg.state.breakpoints.clear();
+            dbg.state.watchpoints.clear();
+        },
+        Some(Ok(id)) => {
+            dbg.state.breakpoints.retain(|bp| bp.id != id);
+            dbg.state.watchpoints.retain(|wp| wp.id != id);
+        },
+        Some(Err(_)) => println!("Usage: delete [<id>]"),
+    }
 }
-fn cmd_list(cpu: &Cpu, _dbg: &mut Debugger, args: &Vec<&str>) {
-    let mut addr = match args.len() {
-        0 => cpu.reg.pc,
-        1 => if let Ok(addr) = u16::from_str_radix(args[0], 16) {
-            addr
+fn cmd_list(cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    let (bank, mut addr) = match args.len() {
+        0 => (None, cpu.reg.pc),
+        1 => if let Some(location) = dbg.parse_location(args[0]) {
+            location
         } else {
-            println!("Usage: list <addr>"); return;
+            println!("Usage: list [<bank>:]<addr>"); return;
         },
         _ => { println!("Too many arguments to list"); return; },
     };
     for _ in 0..10 {
-        addr = print_instr(cpu, addr);
+        addr = print_instr(cpu, &dbg.symbols, bank, addr);
     }
 }
-fn cmd_step(_cpu: &Cpu, dbg: &mut Debugger, args: &Vec<&str>) {
+fn cmd_step(_cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
     let steps = match args.len() {
         0 => 1,
         1 => if let Ok(addr) = u32::from_str(args[0]) {
@@ -170,25 +544,350 @@ fn cmd_step(_cpu: &Cpu, dbg: &mut Debugger, args: &Vec<&str>) {
     dbg.state.steps_remaining = steps;
     dbg.execute = true;
 }
-fn cmd_examine(cpu: &Cpu, _dbg: &mut Debugger, args: &Vec<&str>) {
-    if args.len() != 1 {
-        println!("Usage: x <addr>");
+// Steps over calls and restarts, stopping when they return.
+fn cmd_next(cpu: &mut Cpu, dbg: &mut Debugger, _args: &[&str]) {
+    let mut next = cpu.reg.pc;
+    match cpu.fetch(&mut next) {
+        Instr::Call(..) | Instr::Restart(_) => dbg.state.set_run_to(RunTo::Next(next, cpu.reg.sp)),
+        _ => dbg.state.steps_remaining = 1,
+    }
+    dbg.execute = true;
+}
+fn cmd_finish(cpu: &mut Cpu, dbg: &mut Debugger, _args: &[&str]) {
+    // Without a known call, assume the return address is on top of
+    // the stack, as it is at the start of a function.
+    let sp = cpu.call_stack().last().map_or(cpu.reg.sp, |frame| frame.sp);
+    dbg.state.set_run_to(RunTo::Finish(sp));
+    dbg.execute = true;
+}
+fn cmd_until(_cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    match args.first().and_then(|arg| dbg.parse_addr(arg)) {
+        Some(addr) if args.len() == 1 => {
+            dbg.state.set_run_to(RunTo::Until(addr));
+            dbg.execute = true;
+        },
+        _ => println!("Usage: until <addr>"),
+    }
+}
+fn cmd_frame(_cpu: &mut Cpu, dbg: &mut Debugger, _args: &[&str]) {
+    dbg.state.set_run_to(RunTo::Frame);
+    dbg.execute = true;
+}
+fn cmd_backtrace(cpu: &mut Cpu, dbg: &mut Debugger, _args: &[&str]) {
+    let pc = cpu.reg.pc;
+    println!("#0  {} (bank {})", describe(&dbg.symbols, cpu.bus.rom_bank(pc), pc), cpu.bus.rom_bank(pc));
+    for (i, frame) in cpu.call_stack().iter().rev().enumerate() {
+        let how = if frame.interrupt { "interrupt" } else { "call" };
+        // The target was called from the bank the return address is in.
+        let target_bank = if frame.target < 0x4000 { 0 } else { frame.return_bank };
+        println!("#{:<2} {} (bank {}), {} to {} from {:04x}",
+                 i + 1, describe(&dbg.symbols, frame.return_bank, frame.return_addr), frame.return_bank,
+                 how, describe(&dbg.symbols, target_bank, frame.target), frame.call_site);
+    }
+}
+// Areas of memory that can be dumped whole.
+// Single letters for the commands used most, many of which would
+// otherwise be ambiguous.
+const ALIASES: [(&str, &str); 9] = [
+    ("b", "breakpoint"), ("c", "continue"), ("d", "delete"), ("l", "list"), ("n", "next"),
+    ("r", "registers"), ("s", "step"), ("w", "watchpoint"), ("x", "xamine"),
+];
+
+const REGIONS: [(&str, u16, u16); 4] = [
+    ("vram", 0x8000, 0x9fff),
+    ("wram", 0xc000, 0xdfff),
+    ("oam", 0xfe00, 0xfe9f),
+    ("hram", 0xff80, 0xfffe),
+];
+
+// Reads `len` bytes from `bank`, or from whatever is mapped, stopping
+// at the end of memory.
+fn read_memory(cpu: &Cpu, bank: Option<usize>, start: u16, len: usize) -> Vec<u8> {
+    let end = ::std::cmp::min(start as usize + len, 0x10000);
+    (start as usize..end).map(|addr| match bank {
+        Some(bank) => cpu.bus.peek_bank(bank, addr as u16),
+        None => cpu.bus.peek(addr as u16),
+    }).collect()
+}
+// Prints 16 bytes a line, followed by them as ASCII.
+fn print_hexdump(bank: Option<usize>, start: u16, bytes: &[u8]) {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = line.iter()
+            .map(|&byte| if (0x20..0x7f).contains(&byte) { byte as char } else { '.' })
+            .collect();
+        println!("{}: {:<47}  |{}|", location(bank, start + i as u16 * 16), hex.join(" "), ascii);
+    }
+}
+fn cmd_examine(cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    const USAGE: &str = "Usage: x[/<count>] [<bank>:]<addr>";
+    let (count, rest) = match args.first() {
+        Some(arg) if arg.starts_with('/') => match usize::from_str(&arg[1..]) {
+            Ok(count) if count > 0 => (count, &args[1..]),
+            _ => { println!("{}", USAGE); return; }
+        },
+        _ => (1, args),
+    };
+    match (rest.len(), rest.first().and_then(|arg| dbg.parse_location(arg))) {
+        (1, Some((bank, addr))) => print_hexdump(bank, addr, &read_memory(cpu, bank, addr, count)),
+        _ => println!("{}", USAGE),
+    }
+}
+// Writes memory to a file, either a range or a whole area such as VRAM.
+// Cartridge RAM is written with every bank.
+fn cmd_dump(cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    const USAGE: &str = "Usage: dump <start> <end> <file> | dump <vram|wram|oam|hram|sram> <file>";
+    let (bytes, file) = match args.len() {
+        2 if args[0] == "sram" => (cpu.bus.cartridge_ram().to_vec(), args[1]),
+        2 => match REGIONS.iter().find(|region| region.0 == args[0]) {
+            Some(&(_, start, end)) => (read_memory(cpu, None, start, (end - start) as usize + 1), args[1]),
+            None => { println!("{}", USAGE); return; }
+        },
+        3 => match (dbg.parse_location(args[0]), dbg.parse_location(args[1])) {
+            (Some((bank, start)), Some((end_bank, end)))
+                if end >= start && (end_bank.is_none() || end_bank == bank) =>
+                (read_memory(cpu, bank, start, (end - start) as usize + 1), args[2]),
+            _ => { println!("{}", USAGE); return; }
+        },
+        _ => { println!("{}", USAGE); return; }
+    };
+    match File::create(file).and_then(|mut f| f.write_all(&bytes)) {
+        Ok(()) => println!("Wrote {} bytes to {}", bytes.len(), file),
+        Err(e) => println!("Couldn't write {}: {}", file, e),
+    }
+}
+// Writes the contents of a file over memory, the same way write does.
+fn cmd_load(cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    let addr = match args.get(1).and_then(|arg| dbg.parse_addr(arg)) {
+        Some(addr) if args.len() == 2 => addr,
+        _ => { println!("Usage: load <file> <addr>"); return; }
+    };
+    let mut bytes = Vec::new();
+    if let Err(e) = File::open(args[0]).and_then(|mut f| f.read_to_end(&mut bytes)) {
+        println!("Couldn't read {}: {}", args[0], e);
+        return;
+    }
+    if addr as usize + bytes.len() > 0x10000 {
+        println!("{} bytes don't fit at {:04x}", bytes.len(), addr);
+        return;
+    }
+    for (i, &byte) in bytes.iter().enumerate() {
+        cpu.bus.patch(addr + i as u16, byte);
+    }
+    println!("Loaded {} bytes at {:04x}", bytes.len(), addr);
+}
+// Assembles one instruction given after the address, or reads
+// instructions until a blank line, writing them over memory.
+fn cmd_assemble(cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    let mut addr = match args.first().and_then(|arg| dbg.parse_addr(arg)) {
+        Some(addr) => addr,
+        None => { println!("Usage: assemble <addr> [<instruction>]"); return; }
+    };
+    if args.len() > 1 {
+        assemble_at(cpu, &mut addr, &args[1..].join(" "));
+        return;
+    }
+    while let Ok(line) = dbg.readline.readline(&format!("{:04x}: ", addr)) {
+        if line.trim().is_empty() {
+            break;
+        }
+        assemble_at(cpu, &mut addr, &line);
+    }
+}
+fn assemble_at(cpu: &mut Cpu, addr: &mut u16, text: &str) {
+    match assembler::assemble(text) {
+        Ok(bytes) => for byte in bytes {
+            cpu.bus.patch(*addr, byte);
+            *addr = addr.wrapping_add(1);
+        },
+        Err(e) => println!("{}", e),
+    }
+}
+// Sets a register to the value of an expression, e.g. "set a [c000]+1".
+fn cmd_set(cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    if args.len() < 2 {
+        println!("Usage: set <a|b|c|d|e|h|l|f|af|bc|de|hl|sp|pc|ime> <expression>");
         return;
     }
-    if let Ok(addr) = u16::from_str_radix(args[0], 16) {
-        println!("0x{:4x}: {:2x}", addr, cpu.bus.r8(addr));
+    let value = match dbg.parse_expr(&args[1..].join(" ")).and_then(|expr| expr.eval(&cpu.reg, &cpu.bus)) {
+        Ok(value) => value,
+        Err(e) => { println!("{}", e); return; }
+    };
+    let reg = &mut cpu.reg;
+    match &args[0].to_lowercase()[..] {
+        "a" => reg.w8(Reg8::A, value as u8),
+        "b" => reg.w8(Reg8::B, value as u8),
+        "c" => reg.w8(Reg8::C, value as u8),
+        "d" => reg.w8(Reg8::D, value as u8),
+        "e" => reg.w8(Reg8::E, value as u8),
+        "h" => reg.w8(Reg8::H, value as u8),
+        "l" => reg.w8(Reg8::L, value as u8),
+        "f" => {
+            let a = reg.r8(Reg8::A);
+            reg.w16(Reg16::AF, (a as u16) << 8 | value as u8 as u16);
+        },
+        "af" => reg.w16(Reg16::AF, value as u16),
+        "bc" => reg.w16(Reg16::BC, value as u16),
+        "de" => reg.w16(Reg16::DE, value as u16),
+        "hl" => reg.w16(Reg16::HL, value as u16),
+        "sp" => reg.sp = value as u16,
+        "pc" => reg.pc = value as u16,
+        "ime" => cpu.set_interrupts_enabled(value != 0),
+        other => println!("Unknown register: {}", other),
+    }
+}
+// Writes bytes over memory, patching ROM in place.
+fn cmd_write(cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    let addr = args.first().and_then(|arg| dbg.parse_addr(arg));
+    let bytes: Result<Vec<u8>, _> = args.iter().skip(1).map(|arg| u8::from_str_radix(arg, 16)).collect();
+    match (addr, bytes) {
+        (Some(addr), Ok(ref bytes)) if !bytes.is_empty() => {
+            for (i, &byte) in bytes.iter().enumerate() {
+                cpu.bus.patch(addr.wrapping_add(i as u16), byte);
+            }
+        },
+        _ => println!("Usage: write <addr> <byte>..."),
+    }
+}
+fn cmd_fill(cpu: &mut Cpu, dbg: &mut Debugger, args: &[&str]) {
+    let addr = |i: usize| args.get(i).and_then(|arg| dbg.parse_addr(arg));
+    let byte = args.get(2).and_then(|arg| u8::from_str_radix(arg, 16).ok());
+    match (addr(0), addr(1), byte) {
+        (Some(start), Some(end), Some(byte)) if args.len() == 3 && start <= end => {
+            for addr in start..=end {
+                cpu.bus.patch(addr, byte);
+            }
+        },
+        _ => println!("Usage: fill <start> <end> <byte>"),
+    }
+}
+// Toggles a flag, or sets it to 0 or 1.
+fn cmd_flag(cpu: &mut Cpu, _dbg: &mut Debugger, args: &[&str]) {
+    let reg = &mut cpu.reg;
+    let flag = match args.first().map(|arg| arg.to_lowercase()) {
+        Some(ref name) if name == "z" => &mut reg.f_z,
+        Some(ref name) if name == "n" => &mut reg.f_n,
+        Some(ref name) if name == "h" => &mut reg.f_h,
+        Some(ref name) if name == "c" => &mut reg.f_c,
+        _ => { println!("Usage: flag <z|n|h|c> [0|1]"); return; }
+    };
+    match args.get(1) {
+        None => *flag = !*flag,
+        Some(&"0") => *flag = false,
+        Some(&"1") => *flag = true,
+        Some(_) => { println!("Usage: flag <z|n|h|c> [0|1]"); return; }
     }
+    println!(" A F   B C   D E   H L    PC SP\n{}", reg);
 }
 
+// Names the bits of a register that are set, from the top.
+fn set_bits(value: u8, names: &[&str; 8]) -> String {
+    let set: Vec<&str> = (0..8).rev().filter(|bit| value & (1 << bit) != 0).map(|bit| names[7 - bit]).collect();
+    if set.is_empty() { "none".to_string() } else { set.join(" ") }
+}
+fn cmd_lcd(cpu: &mut Cpu, _dbg: &mut Debugger, _args: &[&str]) {
+    let gpu = cpu.bus.gpu();
+    let (control, stat) = (gpu.get_control(), gpu.get_stat());
+    let bit = |value: u8, n: u8| value & (1 << n) != 0;
+    println!("LCDC {:02x}: LCD {}, window {} using {}, BG {} using {}, tiles at {}, sprites {} {}",
+             control,
+             if bit(control, 7) { "on" } else { "off" },
+             if bit(control, 5) { "on" } else { "off" },
+             if bit(control, 6) { "9c00" } else { "9800" },
+             if bit(control, 0) { "on" } else { "off" },
+             if bit(control, 3) { "9c00" } else { "9800" },
+             if bit(control, 4) { "8000" } else { "8800" },
+             if bit(control, 1) { "on" } else { "off" },
+             if bit(control, 2) { "8x16" } else { "8x8" });
+    const MODES: [&str; 4] = ["HBlank", "VBlank", "OAM search", "drawing"];
+    println!("STAT {:02x}: mode {} ({}), LY {} LYC, interrupts on {}",
+             stat, stat & 3, MODES[stat as usize & 3],
+             if bit(stat, 2) { "==" } else { "!=" },
+             set_bits(stat & 0x78, &["", "LYC", "OAM", "VBlank", "HBlank", "", "", ""]));
+    println!("LY {}, LYC {}, {} cycles into the line", gpu.get_ly(), gpu.get_ly_compare(), gpu.line_cycles());
+    println!("SCX {}, SCY {}, WX {}, WY {}", gpu.get_scroll_x(), gpu.get_scroll_y(), gpu.get_window_x(), gpu.get_window_y());
+    println!("BGP {:02x}, OBP0 {:02x}, OBP1 {:02x}", gpu.get_bg_palette(), gpu.get_obj_palette(0), gpu.get_obj_palette(1));
+}
+fn cmd_timer(cpu: &mut Cpu, _dbg: &mut Debugger, _args: &[&str]) {
+    const FREQUENCIES: [u32; 4] = [4096, 262144, 65536, 16384];
+    let timer = cpu.bus.timer();
+    let control = timer.get_control();
+    println!("DIV {:02x}, internal counter {:04x}", timer.get_divider(), timer.get_system_counter());
+    println!("TIMA {:02x}, TMA {:02x}{}", timer.get_counter(), timer.get_modulo(),
+             if timer.reload_pending() { ", reloading" } else { "" });
+    println!("TAC {:02x}: {}, {} Hz", control, if control & 0x4 != 0 { "running" } else { "stopped" },
+             FREQUENCIES[control as usize & 3]);
+}
+fn cmd_interrupts(cpu: &mut Cpu, _dbg: &mut Debugger, _args: &[&str]) {
+    const SOURCES: [(&str, Interrupt); 5] = [
+        ("VBlank", Interrupt::VBLANK),
+        ("LCD STAT", Interrupt::LCD_STAT),
+        ("Timer", Interrupt::TIMER),
+        ("Serial", Interrupt::SERIAL),
+        ("Joypad", Interrupt::JOYPAD),
+    ];
+    let (enabled, requested) = (cpu.bus.interrupts_enabled(), cpu.bus.interrupts_requested());
+    print!("IME {}", if cpu.interrupts_enabled() { 1 } else { 0 });
+    if cpu.interrupts_pending_enable() {
+        print!(" (after the next instruction)");
+    }
+    if cpu.is_halted() {
+        print!(", halted");
+    }
+    if cpu.is_stopped() {
+        print!(", stopped");
+    }
+    println!();
+    println!("IE {:02x}, IF {:02x}", enabled.bits(), requested.bits());
+    for &(name, interrupt) in SOURCES.iter() {
+        println!("  {:<8}  IE {}  IF {}{}", name,
+                 enabled.contains(interrupt) as u8, requested.contains(interrupt) as u8,
+                 if enabled.contains(interrupt) && requested.contains(interrupt) { "  pending" } else { "" });
+    }
+}
+fn cmd_dma(cpu: &mut Cpu, _dbg: &mut Debugger, _args: &[&str]) {
+    let dma = cpu.bus.dma();
+    print!("DMA {:02x}: ", dma.get_address());
+    match dma.get_progress() {
+        Some(copied) => print!("copying {:04x}-{:04x} to OAM, {} of 160 bytes done",
+                               dma.get_source(), dma.get_source() + 0x9f, copied),
+        None => print!("idle"),
+    }
+    if dma.is_starting() {
+        print!(", starting again from {:02x}00", dma.get_address());
+    }
+    println!();
+}
+fn cmd_cartridge(cpu: &mut Cpu, _dbg: &mut Debugger, _args: &[&str]) {
+    let cart = cpu.bus.cartridge();
+    println!("\"{}\"", cart.title());
+    match cart.controller() {
+        MemoryController::None => println!("No memory controller"),
+        MemoryController::MBC1 { rom_bank, upper_bits, ram_banking } =>
+            println!("MBC1: ROM bank {:02x}, upper bits {}, {} banking mode",
+                     rom_bank, upper_bits, if ram_banking { "RAM" } else { "ROM" }),
+        MemoryController::MBC2(bank) => println!("MBC2: ROM bank {:02x}", bank),
+    }
+    println!("ROM: {} banks, {:02x} at 0000-3fff, {:02x} at 4000-7fff",
+             cart.rom_banks(), cart.bank_at(0x0000), cart.bank_at(0x4000));
+    if cart.ram().is_empty() {
+        println!("RAM: none");
+    } else {
+        println!("RAM: {} bytes in {} bank{}, {:02x} at a000-bfff, {}{}",
+                 cart.ram().len(), cart.ram_banks(), if cart.ram_banks() == 1 { "" } else { "s" },
+                 cart.ram_bank(), if cart.ram_enabled() { "enabled" } else { "disabled" },
+                 if cart.has_battery() { ", battery backed" } else { "" });
+    }
+}
 fn print_sprite(i: u16, cpu: &Cpu, verbose: bool) {
     const SPRITE_RAM_ADDR: u16 = 0xfe00;
     const GPU_CONTROL_ADDR: u16 = 0xff40;
     const TILE_RAM_ADDR: u16 = 0x8000;
 
-    let y = cpu.bus.r8(SPRITE_RAM_ADDR + i * 4);
-    let x = cpu.bus.r8(SPRITE_RAM_ADDR + i * 4 + 1);
-    let t = cpu.bus.r8(SPRITE_RAM_ADDR + i * 4 + 2);
-    let f = cpu.bus.r8(SPRITE_RAM_ADDR + i * 4 + 3);
+    let y = cpu.bus.peek(SPRITE_RAM_ADDR + i * 4);
+    let x = cpu.bus.peek(SPRITE_RAM_ADDR + i * 4 + 1);
+    let t = cpu.bus.peek(SPRITE_RAM_ADDR + i * 4 + 2);
+    let f = cpu.bus.peek(SPRITE_RAM_ADDR + i * 4 + 3);
 
     if !verbose && x == 0 && y == 0 && t == 0 && f == 0 {
         return;
@@ -204,13 +903,13 @@ fn print_sprite(i: u16, cpu: &Cpu, verbose: bool) {
     println!();
 
     if verbose {
-        let control = cpu.bus.r8(GPU_CONTROL_ADDR);
+        let control = cpu.bus.peek(GPU_CONTROL_ADDR);
         let num_lines = if control & (1 << 2) != 0 { 16 } else { 8 };
         let base_tile = TILE_RAM_ADDR + (t as u16) * 16;
         for i in 0..num_lines {
             let line_addr = base_tile + i * 2;
-            let low  = cpu.bus.r8(line_addr);
-            let high = cpu.bus.r8(line_addr + 1);
+            let low  = cpu.bus.peek(line_addr);
+            let high = cpu.bus.peek(line_addr + 1);
             print!("\t");
             for j in 0..8 {
                 print!("{}",
@@ -222,7 +921,7 @@ fn print_sprite(i: u16, cpu: &Cpu, verbose: bool) {
     }
 }
 
-fn cmd_sprites(cpu: &Cpu, _dbg: &mut Debugger, args: &Vec<&str>) {
+fn cmd_sprites(cpu: &mut Cpu, _dbg: &mut Debugger, args: &[&str]) {
     if args.len() > 1 {
         println!("Usage: sprites | sprites <i>");
         return;
@@ -0,0 +1,479 @@
+use std::collections::{BTreeMap,BTreeSet};
+use std::fs::File;
+use std::io::{self,BufWriter,Write};
+use std::path::Path;
+
+use instructions::{Cond,Instr,decode};
+use registers::{Reg8,Reg16,Indirect};
+
+pub const BANK_SIZE: usize = 0x4000;
+
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum Syntax {
+    // The syntax used by the debugger.
+    Native,
+    // Syntax that RGBDS can assemble back into the same bytes.
+    Rgbds,
+}
+
+pub type Labels = BTreeMap<u16, String>;
+
+// Where a CPU address lives in the ROM file when `bank` is mapped at
+// 0x4000-0x7fff.
+pub fn rom_offset(bank: usize, addr: u16) -> Option<usize> {
+    match addr {
+        0x0000 ..= 0x3fff => Some(addr as usize),
+        0x4000 ..= 0x7fff => Some(bank * BANK_SIZE + (addr as usize - BANK_SIZE)),
+        _ => None
+    }
+}
+
+// The bank an address belongs to when `bank` is mapped at 0x4000.
+pub fn bank_for(bank: usize, addr: u16) -> usize {
+    if addr < 0x4000 { 0 } else { bank }
+}
+
+pub fn label_name(bank: usize, addr: u16) -> String {
+    if addr < 0x4000 {
+        format!("label_{:04x}", addr)
+    } else {
+        format!("label_{:02x}_{:04x}", bank, addr)
+    }
+}
+
+// Where a jump, call or restart goes, given the address just after it.
+pub fn branch_target(instr: &Instr, next: u16) -> Option<u16> {
+    match *instr {
+        Instr::Jump(addr, _) | Instr::Call(addr, _) => Some(addr),
+        Instr::JumpRelative(offset, _) => Some(next.wrapping_add(offset as i16 as u16)),
+        Instr::Restart(index) => Some(index as u16 * 8),
+        _ => None
+    }
+}
+
+// Formats an instruction that ends just before `next`, naming branch
+// targets that have labels.
+pub fn format_instr(instr: &Instr, next: u16, syntax: Syntax, labels: &Labels) -> String {
+    let target = |addr: u16| match (labels.get(&addr), syntax) {
+        (Some(label), _) => label.clone(),
+        (None, Syntax::Native) => format!("{:#06x}", addr),
+        (None, Syntax::Rgbds) => format!("${:04x}", addr),
+    };
+    match (syntax, *instr) {
+        (Syntax::Native, Instr::JumpRelative(_, Cond::Always)) |
+        (Syntax::Native, Instr::Jump(_, Cond::Always)) |
+        (Syntax::Native, Instr::Call(_, Cond::Always)) => {
+            let name = match *instr { Instr::JumpRelative(..) => "JR", Instr::Jump(..) => "JP", _ => "CALL" };
+            format!("{} {}", name, target(branch_target(instr, next).unwrap()))
+        },
+        (Syntax::Native, Instr::JumpRelative(_, cond)) |
+        (Syntax::Native, Instr::Jump(_, cond)) |
+        (Syntax::Native, Instr::Call(_, cond)) => {
+            let name = match *instr { Instr::JumpRelative(..) => "JR", Instr::Jump(..) => "JP", _ => "CALL" };
+            format!("{}{},{}", name, cond, target(branch_target(instr, next).unwrap()))
+        },
+        (Syntax::Native, _) => instr.to_string(),
+        (Syntax::Rgbds, _) => rgbds(instr, &target(branch_target(instr, next).unwrap_or(0))),
+    }
+}
+
+fn rgbds_reg8(reg: Reg8) -> &'static str {
+    match reg {
+        Reg8::A => "a", Reg8::B => "b", Reg8::C => "c", Reg8::D => "d",
+        Reg8::E => "e", Reg8::H => "h", Reg8::L => "l", Reg8::HL => "[hl]",
+    }
+}
+
+fn rgbds_reg16(reg: Reg16) -> &'static str {
+    match reg {
+        Reg16::AF => "af", Reg16::BC => "bc", Reg16::DE => "de", Reg16::HL => "hl", Reg16::SP => "sp",
+    }
+}
+
+fn rgbds_indirect(indirect: Indirect) -> &'static str {
+    match indirect {
+        Indirect::BC => "[bc]", Indirect::DE => "[de]", Indirect::HLPlus => "[hl+]", Indirect::HLMinus => "[hl-]",
+    }
+}
+
+fn rgbds_cond(cond: Cond) -> &'static str {
+    match cond {
+        Cond::Z => "z, ", Cond::NZ => "nz, ", Cond::C => "c, ", Cond::NC => "nc, ", Cond::Always => "",
+    }
+}
+
+fn rgbds(instr: &Instr, target: &str) -> String {
+    match *instr {
+        Instr::Bad(opcode) => format!("db ${:02x}", opcode),
+        Instr::Nop => "nop".to_string(),
+        Instr::Stop => "stop".to_string(),
+        Instr::StoreSP(addr) => format!("ld [${:04x}], sp", addr),
+        Instr::JumpRelative(_, cond) => format!("jr {}{}", rgbds_cond(cond), target),
+        Instr::LoadImm16(reg, imm) => format!("ld {}, ${:04x}", rgbds_reg16(reg), imm),
+        Instr::AddHL(reg) => format!("add hl, {}", rgbds_reg16(reg)),
+        Instr::StoreA(indirect) => format!("ld {}, a", rgbds_indirect(indirect)),
+        Instr::LoadA(indirect) => format!("ld a, {}", rgbds_indirect(indirect)),
+        Instr::Inc16(reg) => format!("inc {}", rgbds_reg16(reg)),
+        Instr::Dec16(reg) => format!("dec {}", rgbds_reg16(reg)),
+        Instr::Inc8(reg) => format!("inc {}", rgbds_reg8(reg)),
+        Instr::Dec8(reg) => format!("dec {}", rgbds_reg8(reg)),
+        Instr::LoadImm8(reg, imm) => format!("ld {}, ${:02x}", rgbds_reg8(reg), imm),
+        Instr::RotateALeft => "rlca".to_string(),
+        Instr::RotateALeftCarry => "rla".to_string(),
+        Instr::RotateARight => "rrca".to_string(),
+        Instr::RotateARightCarry => "rra".to_string(),
+        Instr::DecimalAdjust => "daa".to_string(),
+        Instr::Complement => "cpl".to_string(),
+        Instr::ComplementCarry => "ccf".to_string(),
+        Instr::SetCarry => "scf".to_string(),
+        Instr::Halt => "halt".to_string(),
+        Instr::RegCopy(to, from) => format!("ld {}, {}", rgbds_reg8(to), rgbds_reg8(from)),
+        Instr::Add(reg) => format!("add a, {}", rgbds_reg8(reg)),
+        Instr::AddImm(imm) => format!("add a, ${:02x}", imm),
+        Instr::AddCarry(reg) => format!("adc a, {}", rgbds_reg8(reg)),
+        Instr::AddCarryImm(imm) => format!("adc a, ${:02x}", imm),
+        Instr::Sub(reg) => format!("sub {}", rgbds_reg8(reg)),
+        Instr::SubImm(imm) => format!("sub ${:02x}", imm),
+        Instr::SubCarry(reg) => format!("sbc a, {}", rgbds_reg8(reg)),
+        Instr::SubCarryImm(imm) => format!("sbc a, ${:02x}", imm),
+        Instr::And(reg) => format!("and {}", rgbds_reg8(reg)),
+        Instr::AndImm(imm) => format!("and ${:02x}", imm),
+        Instr::Xor(reg) => format!("xor {}", rgbds_reg8(reg)),
+        Instr::XorImm(imm) => format!("xor ${:02x}", imm),
+        Instr::Or(reg) => format!("or {}", rgbds_reg8(reg)),
+        Instr::OrImm(imm) => format!("or ${:02x}", imm),
+        Instr::Comp(reg) => format!("cp {}", rgbds_reg8(reg)),
+        Instr::CompImm(imm) => format!("cp ${:02x}", imm),
+        Instr::Ret(Cond::Always) => "ret".to_string(),
+        Instr::Ret(cond) => format!("ret {}", rgbds_cond(cond).trim_end_matches(", ")),
+        Instr::RetI => "reti".to_string(),
+        Instr::StoreIO(imm) => format!("ldh [$ff{:02x}], a", imm),
+        Instr::LoadIO(imm) => format!("ldh a, [$ff{:02x}]", imm),
+        Instr::StackAdjust(imm) => format!("add sp, {}", imm),
+        Instr::LoadLocalAddr(imm) => format!("ld hl, sp{:+}", imm),
+        Instr::Pop(reg) => format!("pop {}", rgbds_reg16(reg)),
+        Instr::Push(reg) => format!("push {}", rgbds_reg16(reg)),
+        Instr::Jump(_, cond) => format!("jp {}{}", rgbds_cond(cond), target),
+        Instr::JumpHL => "jp hl".to_string(),
+        Instr::LoadStackHL => "ld sp, hl".to_string(),
+        Instr::StoreIOC => "ldh [c], a".to_string(),
+        Instr::LoadIOC => "ldh a, [c]".to_string(),
+        Instr::StoreGlobal(addr) => format!("ld [${:04x}], a", addr),
+        Instr::LoadGlobal(addr) => format!("ld a, [${:04x}]", addr),
+        Instr::DisableInterrupts => "di".to_string(),
+        Instr::EnableInterrupts => "ei".to_string(),
+        Instr::Call(_, cond) => format!("call {}{}", rgbds_cond(cond), target),
+        Instr::Restart(index) => format!("rst ${:02x}", index * 8),
+        Instr::Bit(reg, bit) => format!("bit {}, {}", bit, rgbds_reg8(reg)),
+        Instr::Reset(reg, bit) => format!("res {}, {}", bit, rgbds_reg8(reg)),
+        Instr::Set(reg, bit) => format!("set {}, {}", bit, rgbds_reg8(reg)),
+        Instr::RotateLeft(reg) => format!("rlc {}", rgbds_reg8(reg)),
+        Instr::RotateLeftCarry(reg) => format!("rl {}", rgbds_reg8(reg)),
+        Instr::RotateRight(reg) => format!("rrc {}", rgbds_reg8(reg)),
+        Instr::RotateRightCarry(reg) => format!("rr {}", rgbds_reg8(reg)),
+        Instr::ShiftLeft(reg) => format!("sla {}", rgbds_reg8(reg)),
+        Instr::ShiftRightLogical(reg) => format!("srl {}", rgbds_reg8(reg)),
+        Instr::ShiftRightArith(reg) => format!("sra {}", rgbds_reg8(reg)),
+        Instr::SwapBytes(reg) => format!("swap {}", rgbds_reg8(reg)),
+    }
+}
+
+// Formats an instruction for RGBDS from its bytes. STOP is always
+// assembled with a zero padding byte, so any other padding is kept as
+// data to reassemble to the same bytes.
+fn rgbds_line(instr: &Instr, bytes: &[u8], next: u16, labels: &Labels) -> String {
+    match *instr {
+        Instr::Stop if bytes[1] != 0 => format_bytes(bytes, Syntax::Rgbds),
+        _ => format_instr(instr, next, Syntax::Rgbds, labels),
+    }
+}
+
+pub fn format_bytes(bytes: &[u8], syntax: Syntax) -> String {
+    let values: Vec<String> = match syntax {
+        Syntax::Native => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        Syntax::Rgbds => bytes.iter().map(|b| format!("${:02x}", b)).collect(),
+    };
+    match syntax {
+        Syntax::Native => values.join(" "),
+        Syntax::Rgbds => format!("db {}", values.join(", ")),
+    }
+}
+
+// Lists the instructions from `from` to `to` (inclusive) with `bank`
+// mapped at 0x4000, labelling branch targets inside the listing. A
+// range that runs from bank 0 on into `bank` is listed as two parts,
+// as instructions can't run across from one to the other.
+pub fn disassemble<W: Write>(out: &mut W, rom: &[u8], bank: usize, from: u16, to: u16,
+                             syntax: Syntax) -> io::Result<()> {
+    let to = ::std::cmp::min(to, 0x7fff);
+    let mut regions = Vec::new();
+    if from < 0x4000 {
+        regions.push((from, ::std::cmp::min(to, 0x3fff)));
+    }
+    if to >= 0x4000 {
+        regions.push((::std::cmp::max(from, 0x4000), to));
+    }
+
+    // (address, bytes, instruction) for each region; truncated
+    // instructions are data.
+    let mut listings = Vec::new();
+    for &(from, to) in regions.iter() {
+        let mut lines = Vec::new();
+        let mut addr = from as u32;
+        while addr <= to as u32 {
+            let start = match rom_offset(bank, addr as u16) {
+                Some(offset) if offset < rom.len() => offset,
+                _ => break,
+            };
+            let end = ::std::cmp::min(start + (to as u32 - addr) as usize + 1, rom.len());
+            let bytes = &rom[start..end];
+            let (instr, length) = match decode(bytes) {
+                Some((instr, length)) => (Some(instr), length),
+                None => (None, bytes.len()),
+            };
+            lines.push((addr as u16, &bytes[..length], instr));
+            addr += length as u32;
+        }
+        listings.push((from, lines));
+    }
+
+    let mut labels = Labels::new();
+    for &(addr, bytes, instr) in listings.iter().flat_map(|(_, lines)| lines.iter()) {
+        let next = addr.wrapping_add(bytes.len() as u16);
+        if let Some(target) = instr.and_then(|i| branch_target(&i, next)) {
+            if listings.iter().any(|(_, lines)| lines.iter().any(|&(a, _, _)| a == target)) {
+                labels.insert(target, label_name(bank_for(bank, target), target));
+            }
+        }
+    }
+
+    for (i, &(from, ref lines)) in listings.iter().enumerate() {
+        match syntax {
+            Syntax::Rgbds if from < 0x4000 =>
+                writeln!(out, "SECTION \"disasm_{:04x}\", ROM0[${:04x}]", from, from)?,
+            Syntax::Rgbds =>
+                writeln!(out, "SECTION \"disasm_{:02x}_{:04x}\", ROMX[${:04x}], BANK[${:02x}]",
+                         bank, from, from, bank)?,
+            Syntax::Native if i > 0 => writeln!(out)?,
+            Syntax::Native => {},
+        }
+        for &(addr, bytes, instr) in lines.iter() {
+            if let Some(label) = labels.get(&addr) {
+                writeln!(out, "{}:", label)?;
+            }
+            let next = addr.wrapping_add(bytes.len() as u16);
+            let text = match (instr, syntax) {
+                (Some(instr), Syntax::Native) => format_instr(&instr, next, syntax, &labels),
+                (Some(instr), Syntax::Rgbds) => rgbds_line(&instr, bytes, next, &labels),
+                (None, _) => format_bytes(bytes, Syntax::Rgbds),
+            };
+            match syntax {
+                Syntax::Native => writeln!(out, "  {:02x}:{:04x}  {:<9} {}", bank_for(bank, addr), addr,
+                                           format_bytes(bytes, Syntax::Native), text)?,
+                Syntax::Rgbds => writeln!(out, "\t{}", text)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+enum Byte {
+    Data,
+    // The first byte of an instruction.
+    Code,
+    // The rest of an instruction.
+    Operand,
+}
+
+// Which bytes of a ROM are reachable code, found by following every
+// path from the entry points.
+pub struct CodeMap {
+    bytes: Vec<Byte>,
+    // ROM offsets that are branch targets.
+    targets: BTreeSet<usize>,
+}
+
+impl CodeMap {
+    pub fn is_code(&self, offset: usize) -> bool {
+        self.bytes.get(offset) == Some(&Byte::Code)
+    }
+    pub fn code_bytes(&self) -> usize {
+        self.bytes.iter().filter(|&&b| b != Byte::Data).count()
+    }
+    // The label for the code at a ROM offset, if anything branches there.
+    pub fn label(&self, offset: usize) -> Option<String> {
+        if self.targets.contains(&offset) && self.is_code(offset) {
+            let (bank, addr) = offset_address(offset);
+            Some(label_name(bank, addr))
+        } else {
+            None
+        }
+    }
+}
+
+// The bank and CPU address a ROM offset is seen at.
+pub fn offset_address(offset: usize) -> (usize, u16) {
+    let bank = offset / BANK_SIZE;
+    let addr = if bank == 0 { offset } else { BANK_SIZE + offset % BANK_SIZE };
+    (bank, addr as u16)
+}
+
+// Whether an instruction might change A, for following bank switches.
+fn writes_a(instr: &Instr) -> bool {
+    match *instr {
+        Instr::Nop | Instr::StoreSP(_) | Instr::LoadImm16(..) | Instr::StoreA(_) |
+        Instr::Inc16(_) | Instr::Dec16(_) | Instr::Push(_) | Instr::StoreIO(_) |
+        Instr::StoreIOC | Instr::StoreGlobal(_) | Instr::DisableInterrupts |
+        Instr::EnableInterrupts => false,
+        Instr::LoadImm8(reg, _) | Instr::Inc8(reg) | Instr::Dec8(reg) => reg != Reg8::A,
+        Instr::RegCopy(to, _) => to != Reg8::A,
+        _ => true,
+    }
+}
+
+// Follows code from the entry point, RST vectors and interrupt vectors
+// through every jump, call and restart. Jumps into 0x4000-0x7fff from
+// bank 0 go to whichever bank the code last selected with
+// LD A,n / LD (0x2000),A, if that can be seen; otherwise they are only
+// followed when the ROM has a single switchable bank.
+pub fn trace_code(rom: &[u8]) -> CodeMap {
+    let num_banks = ::std::cmp::max(rom.len().div_ceil(BANK_SIZE), 1);
+    let mut map = CodeMap {
+        bytes: vec![Byte::Data; rom.len()],
+        targets: BTreeSet::new(),
+    };
+    let mut pending: Vec<(usize, u16)> = (0..8).map(|i| (0, i * 8))
+        .chain((0..5).map(|i| (0, 0x40 + i * 8)))
+        .chain(Some((0, 0x100)))
+        .collect();
+
+    while let Some((bank, start)) = pending.pop() {
+        let mut addr = start;
+        let mut a = None;
+        let mut selected = None;
+        loop {
+            let offset = match rom_offset(bank, addr) {
+                Some(offset) if offset < rom.len() => offset,
+                _ => break,
+            };
+            let end = ::std::cmp::min((offset / BANK_SIZE + 1) * BANK_SIZE, rom.len());
+            let (instr, length) = match decode(&rom[offset..end]) {
+                Some((Instr::Bad(_), _)) | None => break,
+                Some(decoded) => decoded,
+            };
+            // Stop at code we've already seen, or bytes that another
+            // path decoded differently.
+            if map.bytes[offset..offset + length].iter().any(|&b| b != Byte::Data) {
+                break;
+            }
+            map.bytes[offset] = Byte::Code;
+            for byte in map.bytes[offset + 1..offset + length].iter_mut() {
+                *byte = Byte::Operand;
+            }
+
+            match instr {
+                Instr::LoadImm8(Reg8::A, value) => a = Some(value as usize),
+                Instr::Xor(Reg8::A) => a = Some(0),
+                Instr::StoreGlobal(0x2000 ..= 0x3fff) =>
+                    selected = a.map(|a| ::std::cmp::max(a, 1) % num_banks),
+                _ if writes_a(&instr) => a = None,
+                _ => {}
+            }
+
+            let next = addr.wrapping_add(length as u16);
+            if let Some(target) = branch_target(&instr, next) {
+                let target_bank = match target {
+                    0x0000 ..= 0x3fff => Some(0),
+                    0x4000 ..= 0x7fff if bank != 0 => Some(bank),
+                    0x4000 ..= 0x7fff if selected.is_some() => selected,
+                    0x4000 ..= 0x7fff if num_banks == 2 => Some(1),
+                    _ => None,
+                };
+                if let Some(target_bank) = target_bank {
+                    if let Some(target_offset) = rom_offset(target_bank, target) {
+                        map.targets.insert(target_offset);
+                        pending.push((target_bank, target));
+                    }
+                }
+            }
+
+            match instr {
+                Instr::Ret(Cond::Always) | Instr::RetI | Instr::JumpHL |
+                Instr::Jump(_, Cond::Always) | Instr::JumpRelative(_, Cond::Always) => break,
+                _ => {}
+            }
+            // Code doesn't run off the end of its bank.
+            if next == 0x4000 || next == 0x8000 {
+                break;
+            }
+            addr = next;
+        }
+    }
+    map
+}
+
+// Writes an RGBDS project that reassembles to the same ROM: one file
+// per bank, with reachable code disassembled and everything else kept
+// as data, and a game.asm that includes them all.
+pub fn write_project(rom: &[u8], dir: &Path) -> io::Result<CodeMap> {
+    ::std::fs::create_dir_all(dir)?;
+    let map = trace_code(rom);
+    let num_banks = rom.len().div_ceil(BANK_SIZE);
+
+    let mut main = BufWriter::new(File::create(dir.join("game.asm"))?);
+    writeln!(main, "; Build with: rgbasm -o game.o game.asm && rgblink -o game.gb game.o")?;
+    for bank in 0..num_banks {
+        let name = format!("bank_{:02x}.asm", bank);
+        writeln!(main, "INCLUDE \"{}\"", name)?;
+        let mut out = BufWriter::new(File::create(dir.join(&name))?);
+        write_bank(&mut out, rom, &map, bank, num_banks)?;
+    }
+    Ok(map)
+}
+
+fn write_bank<W: Write>(out: &mut W, rom: &[u8], map: &CodeMap, bank: usize,
+                        num_banks: usize) -> io::Result<()> {
+    if bank == 0 {
+        writeln!(out, "SECTION \"bank_00\", ROM0[$0000]")?;
+    } else {
+        writeln!(out, "SECTION \"bank_{:02x}\", ROMX[$4000], BANK[${:02x}]", bank, bank)?;
+    }
+
+    // Labels that code in this bank can refer to. Code in bank 0 can't
+    // know which bank is mapped above it, unless there is only one.
+    let mut labels = Labels::new();
+    let upper_bank = if bank != 0 { Some(bank) } else if num_banks == 2 { Some(1) } else { None };
+    for &offset in map.targets.iter() {
+        let (target_bank, addr) = offset_address(offset);
+        if target_bank == 0 || Some(target_bank) == upper_bank {
+            if let Some(label) = map.label(offset) {
+                labels.insert(addr, label);
+            }
+        }
+    }
+
+    let start = bank * BANK_SIZE;
+    let end = ::std::cmp::min(start + BANK_SIZE, rom.len());
+    let mut offset = start;
+    while offset < end {
+        if map.is_code(offset) {
+            let (instr, length) = decode(&rom[offset..end]).expect("Traced code was truncated");
+            let (_, addr) = offset_address(offset);
+            if let Some(label) = map.label(offset) {
+                writeln!(out, "{}:", label)?;
+            }
+            let next = addr.wrapping_add(length as u16);
+            writeln!(out, "\t{}", rgbds_line(&instr, &rom[offset..offset + length], next, &labels))?;
+            offset += length;
+        } else {
+            let mut data_end = offset;
+            while data_end < end && data_end < offset + 16 && !map.is_code(data_end) {
+                data_end += 1;
+            }
+            writeln!(out, "\t{}", format_bytes(&rom[offset..data_end], Syntax::Rgbds))?;
+            offset = data_end;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,295 @@
+use bus::MemoryBus;
+use registers::{Registers,Reg8,Reg16};
+use symbols::Symbols;
+
+// Expressions over registers, flags and memory for the debugger, e.g.
+//   A == 0x3f && [ff44] > 90
+// Numbers are decimal unless they start with 0x or $, except that a
+// bare address in brackets is hex like every other debugger address
+// (register names win, so [BC] is the byte BC points to).
+// Labels can be used as numbers when there are symbols. Comparisons and
+// logical operators give 1 or 0.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Reg8(Reg8),
+    Reg16(Reg16),
+    F,
+    Pc,
+    Flag(Flag),
+    // The byte at an address.
+    Memory(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum Flag {
+    Z, N, H, C
+}
+
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum UnaryOp {
+    Neg, Not, Complement
+}
+
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum BinaryOp {
+    Or, And, BitOr, BitXor, BitAnd, Eq, Ne, Lt, Le, Gt, Ge,
+    Shl, Shr, Add, Sub, Mul, Div, Rem
+}
+
+impl BinaryOp {
+    fn from(token: &str) -> Option<(BinaryOp, u8)> {
+        let op = match token {
+            "||" => (BinaryOp::Or, 1),
+            "&&" => (BinaryOp::And, 2),
+            "|" => (BinaryOp::BitOr, 3),
+            "^" => (BinaryOp::BitXor, 4),
+            "&" => (BinaryOp::BitAnd, 5),
+            "==" => (BinaryOp::Eq, 6),
+            "!=" => (BinaryOp::Ne, 6),
+            "<" => (BinaryOp::Lt, 7),
+            "<=" => (BinaryOp::Le, 7),
+            ">" => (BinaryOp::Gt, 7),
+            ">=" => (BinaryOp::Ge, 7),
+            "<<" => (BinaryOp::Shl, 8),
+            ">>" => (BinaryOp::Shr, 8),
+            "+" => (BinaryOp::Add, 9),
+            "-" => (BinaryOp::Sub, 9),
+            "*" => (BinaryOp::Mul, 10),
+            "/" => (BinaryOp::Div, 10),
+            "%" => (BinaryOp::Rem, 10),
+            _ => return None,
+        };
+        Some(op)
+    }
+}
+
+const OPERATORS: [&str; 20] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "|", "^", "&", "<", ">", "+", "-", "*", "/", "%", "!", "~",
+];
+
+#[derive(Clone,Debug,PartialEq)]
+enum Token {
+    // A number or a name; which one depends on where it appears.
+    Word(String),
+    Op(&'static str),
+    Close(char),
+    Open(char),
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_alphanumeric() || c == '$' || c == '_' || c == '.' {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || "$_.@#".contains(c)))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else if c == '(' || c == '[' {
+            tokens.push(Token::Open(c));
+            rest = &rest[1..];
+        } else if c == ')' || c == ']' {
+            tokens.push(Token::Close(c));
+            rest = &rest[1..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            return Err(format!("Unexpected character: {}", c));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: Option<&'a Symbols>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+    fn expect_close(&mut self, c: char) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Close(close)) if close == c => Ok(()),
+            _ => Err(format!("Expected {}", c)),
+        }
+    }
+    // Precedence climbing: parses operators binding tighter than `min`.
+    fn binary(&mut self, min: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(&Token::Op(token)) = self.peek() {
+            let (op, precedence) = match BinaryOp::from(token) {
+                Some((op, precedence)) if precedence > min => (op, precedence),
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.binary(precedence)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+    fn unary(&mut self) -> Result<Expr, String> {
+        let op = match self.peek() {
+            Some(&Token::Op("-")) => UnaryOp::Neg,
+            Some(&Token::Op("!")) => UnaryOp::Not,
+            Some(&Token::Op("~")) => UnaryOp::Complement,
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+    fn symbol(&self, word: &str) -> Option<u16> {
+        self.symbols.and_then(|symbols| symbols.lookup(word)).map(|(_, addr)| addr)
+    }
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Open('(')) => {
+                let expr = self.binary(0)?;
+                self.expect_close(')')?;
+                Ok(expr)
+            },
+            Some(Token::Open('[')) => {
+                let addr = match (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+                    (Some(Token::Word(word)), Some(Token::Close(']')))
+                        if name_expr(word).is_none() && self.symbol(word).is_none() &&
+                            u16::from_str_radix(word, 16).is_ok() => {
+                        self.pos += 1;
+                        Expr::Number(i64::from_str_radix(word, 16).unwrap())
+                    },
+                    _ => self.binary(0)?,
+                };
+                self.expect_close(']')?;
+                Ok(Expr::Memory(Box::new(addr)))
+            },
+            Some(Token::Word(word)) => match self.symbol(&word) {
+                Some(addr) => Ok(Expr::Number(addr as i64)),
+                None => word_expr(&word),
+            },
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+fn name_expr(word: &str) -> Option<Expr> {
+    let expr = match &word.to_uppercase()[..] {
+        "A" => Expr::Reg8(Reg8::A),
+        "B" => Expr::Reg8(Reg8::B),
+        "C" => Expr::Reg8(Reg8::C),
+        "D" => Expr::Reg8(Reg8::D),
+        "E" => Expr::Reg8(Reg8::E),
+        "H" => Expr::Reg8(Reg8::H),
+        "L" => Expr::Reg8(Reg8::L),
+        "F" => Expr::F,
+        "AF" => Expr::Reg16(Reg16::AF),
+        "BC" => Expr::Reg16(Reg16::BC),
+        "DE" => Expr::Reg16(Reg16::DE),
+        "HL" => Expr::Reg16(Reg16::HL),
+        "SP" => Expr::Reg16(Reg16::SP),
+        "PC" => Expr::Pc,
+        "ZF" => Expr::Flag(Flag::Z),
+        "NF" => Expr::Flag(Flag::N),
+        "HF" => Expr::Flag(Flag::H),
+        "CF" => Expr::Flag(Flag::C),
+        _ => return None,
+    };
+    Some(expr)
+}
+
+fn word_expr(word: &str) -> Result<Expr, String> {
+    if let Some(expr) = name_expr(word) {
+        return Ok(expr);
+    }
+    let value = if let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")).or_else(|| word.strip_prefix('$')) {
+        i64::from_str_radix(hex, 16)
+    } else {
+        word.parse()
+    };
+    value.map(Expr::Number).map_err(|_| format!("Unknown name: {}", word))
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        Expr::parse_with_symbols(text, None)
+    }
+    pub fn parse_with_symbols(text: &str, symbols: Option<&Symbols>) -> Result<Expr, String> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0, symbols };
+        let expr = parser.binary(0)?;
+        match parser.next() {
+            None => Ok(expr),
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+        }
+    }
+
+    pub fn eval<B: MemoryBus>(&self, reg: &Registers, bus: &B) -> Result<i64, String> {
+        let value = match *self {
+            Expr::Number(value) => value,
+            Expr::Reg8(reg8) => reg.r8(reg8) as i64,
+            Expr::Reg16(Reg16::SP) => reg.sp as i64,
+            Expr::Reg16(reg16) => reg.r16(reg16) as i64,
+            Expr::F => reg.r16(Reg16::AF) as i64 & 0xff,
+            Expr::Pc => reg.pc as i64,
+            Expr::Flag(flag) => {
+                let set = match flag {
+                    Flag::Z => reg.f_z,
+                    Flag::N => reg.f_n,
+                    Flag::H => reg.f_h,
+                    Flag::C => reg.f_c,
+                };
+                set as i64
+            },
+            Expr::Memory(ref addr) => bus.peek(addr.eval(reg, bus)? as u16) as i64,
+            Expr::Unary(op, ref operand) => {
+                let value = operand.eval(reg, bus)?;
+                match op {
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Complement => !value,
+                }
+            },
+            // These only evaluate the right hand side if they need to.
+            Expr::Binary(BinaryOp::And, ref lhs, ref rhs) =>
+                (lhs.eval(reg, bus)? != 0 && rhs.eval(reg, bus)? != 0) as i64,
+            Expr::Binary(BinaryOp::Or, ref lhs, ref rhs) =>
+                (lhs.eval(reg, bus)? != 0 || rhs.eval(reg, bus)? != 0) as i64,
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                let (lhs, rhs) = (lhs.eval(reg, bus)?, rhs.eval(reg, bus)?);
+                match op {
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitXor => lhs ^ rhs,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::Eq => (lhs == rhs) as i64,
+                    BinaryOp::Ne => (lhs != rhs) as i64,
+                    BinaryOp::Lt => (lhs < rhs) as i64,
+                    BinaryOp::Le => (lhs <= rhs) as i64,
+                    BinaryOp::Gt => (lhs > rhs) as i64,
+                    BinaryOp::Ge => (lhs >= rhs) as i64,
+                    BinaryOp::Shl => lhs.wrapping_shl(rhs as u32),
+                    BinaryOp::Shr => lhs.wrapping_shr(rhs as u32),
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div | BinaryOp::Rem if rhs == 0 => return Err("Division by zero".to_string()),
+                    BinaryOp::Div => lhs.wrapping_div(rhs),
+                    BinaryOp::Rem => lhs.wrapping_rem(rhs),
+                    BinaryOp::And | BinaryOp::Or => unreachable!(),
+                }
+            },
+        };
+        Ok(value)
+    }
+}
@@ -34,7 +34,7 @@ impl Palette {
         for i in 0..4 {
             colours[i] = (raw >> (2 * i)) & 0x3;
         }
-        Palette { colours: colours }
+        Palette { colours }
     }
     #[inline(always)]
     pub fn to_u8(&self) -> u8 {
@@ -387,7 +387,7 @@ impl Gpu {
     }
     #[inline(always)]
     pub fn get_stat(&self) -> u8 {
-        let mut result = self.mode.get_bits();
+        let mut result = 0x80 | self.mode.get_bits();
         if self.ly == self.ly_compare { result |= 1 << 2 };
         if self.hblank_check_enabled  { result |= 1 << 3 };
         if self.vblank_check_enabled  { result |= 1 << 4 };
@@ -396,6 +396,23 @@ impl Gpu {
 
         result
     }
+    // How far the current line has got, from 0 to 455.
+    pub fn line_cycles(&self) -> i32 {
+        let (before, length) = match self.mode {
+            Mode::OamAccess => (0, OAM_ACCESS_CYCLES),
+            Mode::VramAccess => (OAM_ACCESS_CYCLES, VRAM_ACCESS_CYCLES),
+            Mode::HBlank => (OAM_ACCESS_CYCLES + VRAM_ACCESS_CYCLES, HBLANK_CYCLES),
+            Mode::VBlank => (0, VBLANK_CYCLES),
+        };
+        before + length - self.cycles_left
+    }
+    #[inline(always)]
+    pub fn oam_blocked(&self) -> bool {
+        match self.mode {
+            Mode::OamAccess | Mode::VramAccess => self.lcd_enabled,
+            _ => false
+        }
+    }
     #[inline(always)]
     pub fn set_stat(&mut self, value: u8) {
         self.hblank_check_enabled = (value & (1 << 3)) != 0;
@@ -15,6 +15,15 @@ impl Cond {
             _ => unreachable!("Invalid condition code")
         }
     }
+    pub fn bits(&self) -> u8 {
+        match self {
+            Cond::NZ => 0,
+            Cond::Z => 1,
+            Cond::NC => 2,
+            Cond::C => 3,
+            Cond::Always => unreachable!("Unconditional instructions have no condition code")
+        }
+    }
 }
 impl fmt::Display for Cond {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -108,10 +117,10 @@ impl fmt::Display for Instr {
             Instr::Bad(opcode) => write!(f, "<{:02x}>", opcode),
             Instr::Nop => write!(f, "NOP"),
             Instr::Stop => write!(f, "STOP"),
-            Instr::StoreSP(addr) => write!(f, "LD ({:#04x}),SP", addr),
+            Instr::StoreSP(addr) => write!(f, "LD ({:#06x}),SP", addr),
             Instr::JumpRelative(offset, Cond::Always) => write!(f, "JR {}", offset),
             Instr::JumpRelative(offset, cond) => write!(f, "JR{},{}", cond, offset),
-            Instr::LoadImm16(reg, imm) => write!(f, "LD {},{:#04x}", reg, imm),
+            Instr::LoadImm16(reg, imm) => write!(f, "LD {},{:#06x}", reg, imm),
             Instr::AddHL(reg) => write!(f, "ADD HL,{}", reg),
             Instr::StoreA(indirect) => write!(f, "LD {},A", indirect),
             Instr::LoadA(indirect) => write!(f, "LD A,{}", indirect),
@@ -119,7 +128,7 @@ impl fmt::Display for Instr {
             Instr::Dec8(reg) => write!(f, "DEC {}", reg),
             Instr::Inc16(reg) => write!(f, "INC {}", reg),
             Instr::Dec16(reg) => write!(f, "DEC {}", reg),
-            Instr::LoadImm8(reg,imm) => write!(f, "LD {},{:#02x}", reg, imm),
+            Instr::LoadImm8(reg,imm) => write!(f, "LD {},{:#04x}", reg, imm),
             Instr::RotateALeft => write!(f, "RLCA"),
             Instr::RotateALeftCarry => write!(f, "RLA"),
             Instr::RotateARight => write!(f, "RRCA"),
@@ -138,34 +147,35 @@ impl fmt::Display for Instr {
             Instr::Xor(reg) => write!(f, "XOR {}", reg),
             Instr::Or(reg) => write!(f, "OR {}", reg),
             Instr::Comp(reg) => write!(f, "CP {}", reg),
-            Instr::AddImm(imm) => write!(f, "ADD A,{:#02x}", imm),
-            Instr::AddCarryImm(imm) => write!(f, "ADC A,{:#02x}", imm),
-            Instr::SubImm(imm) => write!(f, "SUB {:#02x}", imm),
-            Instr::SubCarryImm(imm) => write!(f, "SBC A,{:#02x}", imm),
-            Instr::AndImm(imm) => write!(f, "AND {:#02x}", imm),
-            Instr::XorImm(imm) => write!(f, "XOR {:#02x}", imm),
-            Instr::OrImm(imm) => write!(f, "OR {:#02x}", imm),
-            Instr::CompImm(imm) => write!(f, "CP {:#02x}", imm),
+            Instr::AddImm(imm) => write!(f, "ADD A,{:#04x}", imm),
+            Instr::AddCarryImm(imm) => write!(f, "ADC A,{:#04x}", imm),
+            Instr::SubImm(imm) => write!(f, "SUB {:#04x}", imm),
+            Instr::SubCarryImm(imm) => write!(f, "SBC A,{:#04x}", imm),
+            Instr::AndImm(imm) => write!(f, "AND {:#04x}", imm),
+            Instr::XorImm(imm) => write!(f, "XOR {:#04x}", imm),
+            Instr::OrImm(imm) => write!(f, "OR {:#04x}", imm),
+            Instr::CompImm(imm) => write!(f, "CP {:#04x}", imm),
             Instr::Ret(cond) => write!(f, "RET{}", cond),
             Instr::RetI => write!(f, "RETI"),
-            Instr::StoreIO(imm) => write!(f, "LD (0xff00+{:#02x}),A", imm),
-            Instr::LoadIO(imm) => write!(f, "LD A,(0xff00+{:#02x})", imm),
-            Instr::StackAdjust(imm) => write!(f, "ADD SP,{:#02x}", imm), // TODO:
-            Instr::LoadLocalAddr(imm) => write!(f, "LD HL,SP{:+#02x}", imm),
+            Instr::StoreIO(imm) => write!(f, "LD (0xff00+{:#04x}),A", imm),
+            Instr::LoadIO(imm) => write!(f, "LD A,(0xff00+{:#04x})", imm),
+            Instr::StackAdjust(imm) => write!(f, "ADD SP,{}", imm),
+            Instr::LoadLocalAddr(imm) => write!(f, "LD HL,SP{:+}", imm),
             Instr::Pop(reg) => write!(f, "POP {}", reg),
             Instr::Push(reg) => write!(f, "PUSH {}", reg),
-            Instr::Jump(addr, Cond::Always) => write!(f, "JP {:x}", addr),
-            Instr::Jump(addr, cond) => write!(f, "JP{},{:x}", cond, addr),
+            Instr::Jump(addr, Cond::Always) => write!(f, "JP {:#06x}", addr),
+            Instr::Jump(addr, cond) => write!(f, "JP{},{:#06x}", cond, addr),
             Instr::JumpHL => write!(f, "JP (HL)"),
-            Instr::LoadStackHL => write!(f, "LD SP, HL"),
-            Instr::LoadIOC => write!(f, "LD A,(0xffoo+C)"),
-            Instr::StoreIOC => write!(f, "LD (0xffoo+C),A"),
-            Instr::LoadGlobal(imm) => write!(f, "LD A,({:#04x})",imm),
-            Instr::StoreGlobal(imm) => write!(f, "LD ({:#04x}),A",imm),
+            Instr::LoadStackHL => write!(f, "LD SP,HL"),
+            Instr::LoadIOC => write!(f, "LD A,(0xff00+C)"),
+            Instr::StoreIOC => write!(f, "LD (0xff00+C),A"),
+            Instr::LoadGlobal(imm) => write!(f, "LD A,({:#06x})", imm),
+            Instr::StoreGlobal(imm) => write!(f, "LD ({:#06x}),A", imm),
             Instr::DisableInterrupts => write!(f, "DI"),
             Instr::EnableInterrupts => write!(f, "EI"),
-            Instr::Call(addr, cond) => write!(f, "CALL{} {:#04x}", cond, addr),
-            Instr::Restart(index) => write!(f, "RST {:#02x}", index * 8),
+            Instr::Call(addr, Cond::Always) => write!(f, "CALL {:#06x}", addr),
+            Instr::Call(addr, cond) => write!(f, "CALL{},{:#06x}", cond, addr),
+            Instr::Restart(index) => write!(f, "RST {:#04x}", index * 8),
             Instr::Bit(reg, bit) => write!(f, "BIT {},{}", bit, reg),
             Instr::Reset(reg, bit) => write!(f, "RES {},{}", bit, reg),
             Instr::Set(reg, bit) => write!(f, "SET {},{}", bit, reg),
@@ -180,3 +190,206 @@ impl fmt::Display for Instr {
         }
     }
 }
+
+// Decodes the instruction at the start of `bytes`, returning it along
+// with its length, or None if `bytes` ends partway through it.
+pub fn decode(bytes: &[u8]) -> Option<(Instr, usize)> {
+    let mut length = 0;
+    let instr = decode_from(|| {
+        let value = bytes.get(length).cloned().unwrap_or(0);
+        length += 1;
+        value
+    });
+    if length <= bytes.len() { Some((instr, length)) } else { None }
+}
+
+// Decodes one instruction, taking its bytes from `next` in order.
+pub fn decode_from<F: FnMut() -> u8>(mut next: F) -> Instr {
+    let opcode = next();
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 7;
+    let z = opcode & 7;
+
+    match (x,y,z) {
+        (0,0,0) => Instr::Nop,
+        (0,1,0) => Instr::StoreSP(imm16(&mut next)),
+        (0,2,0) => {
+            // STOP is followed by a padding byte.
+            next();
+            Instr::Stop
+        },
+        (0,3,0) => Instr::JumpRelative(next() as i8, Cond::Always),
+        (0,4..=7,0) => Instr::JumpRelative(next() as i8, Cond::from(y-4)),
+        (0,_,1) if y & 1 == 0  => Instr::LoadImm16(Reg16::from(y, true), imm16(&mut next)),
+        (0,_,1) => Instr::AddHL(Reg16::from(y, true)),
+        (0,_,2) if y & 1 == 0 => Instr::StoreA(Indirect::from(y)),
+        (0,_,2) => Instr::LoadA(Indirect::from(y)),
+        (0,_,3) if y & 1 == 0 => Instr::Inc16(Reg16::from(y, true)),
+        (0,_,3) => Instr::Dec16(Reg16::from(y, true)),
+        (0,_,4) => Instr::Inc8(Reg8::from(y)),
+        (0,_,5) => Instr::Dec8(Reg8::from(y)),
+        (0,_,6) => Instr::LoadImm8(Reg8::from(y), next()),
+        (0,0,7) => Instr::RotateALeft,
+        (0,1,7) => Instr::RotateARight,
+        (0,2,7) => Instr::RotateALeftCarry,
+        (0,3,7) => Instr::RotateARightCarry,
+        (0,4,7) => Instr::DecimalAdjust,
+        (0,5,7) => Instr::Complement,
+        (0,6,7) => Instr::SetCarry,
+        (0,7,7) => Instr::ComplementCarry,
+        (1,6,6) => Instr::Halt,
+        (1,_,_) => Instr::RegCopy(Reg8::from(y), Reg8::from(z)),
+        (2,0,_) => Instr::Add(Reg8::from(z)),
+        (2,1,_) => Instr::AddCarry(Reg8::from(z)),
+        (2,2,_) => Instr::Sub(Reg8::from(z)),
+        (2,3,_) => Instr::SubCarry(Reg8::from(z)),
+        (2,4,_) => Instr::And(Reg8::from(z)),
+        (2,5,_) => Instr::Xor(Reg8::from(z)),
+        (2,6,_) => Instr::Or(Reg8::from(z)),
+        (2,7,_) => Instr::Comp(Reg8::from(z)),
+        (3,0..=3,0) => Instr::Ret(Cond::from(y)),
+        (3,4,0) => Instr::StoreIO(next()),
+        (3,5,0) => Instr::StackAdjust(next() as i8),
+        (3,6,0) => Instr::LoadIO(next()),
+        (3,7,0) => Instr::LoadLocalAddr(next() as i8),
+        (3,_,1) if y & 1 == 0 => Instr::Pop(Reg16::from(y, false)),
+        (3,1,1) => Instr::Ret(Cond::Always),
+        (3,3,1) => Instr::RetI,
+        (3,5,1) => Instr::JumpHL,
+        (3,7,1) => Instr::LoadStackHL,
+        (3,0..=3,2) => Instr::Jump(imm16(&mut next), Cond::from(y)),
+        (3,4,2) => Instr::StoreIOC,
+        (3,5,2) => Instr::StoreGlobal(imm16(&mut next)),
+        (3,6,2) => Instr::LoadIOC,
+        (3,7,2) => Instr::LoadGlobal(imm16(&mut next)),
+        (3,0,3) => Instr::Jump(imm16(&mut next), Cond::Always),
+        (3,1,3) => {
+            let extended_opcode = next();
+            let operation = extended_opcode >> 6;
+            let y = (extended_opcode >> 3) & 7;
+            let reg = Reg8::from(extended_opcode & 7);
+            match operation {
+                0 => { // Rotations
+                    match y {
+                        0 => Instr::RotateLeft(reg),
+                        1 => Instr::RotateRight(reg),
+                        2 => Instr::RotateLeftCarry(reg),
+                        3 => Instr::RotateRightCarry(reg),
+                        4 => Instr::ShiftLeft(reg),
+                        5 => Instr::ShiftRightArith(reg),
+                        6 => Instr::SwapBytes(reg),
+                        7 => Instr::ShiftRightLogical(reg),
+                        _ => unreachable!("Invalid rotation"),
+                    }
+                },
+                1 => Instr::Bit(reg, y),
+                2 => Instr::Reset(reg, y),
+                3 => Instr::Set(reg, y),
+                _ => unreachable!("Invalid extended instruction")
+            }
+        },
+        (3,6,3) => Instr::DisableInterrupts,
+        (3,7,3) => Instr::EnableInterrupts,
+        (3,0..=3,4) => Instr::Call(imm16(&mut next), Cond::from(y)),
+        (3,_,5) if y & 1 == 0 => Instr::Push(Reg16::from(y, false)),
+        (3,1,5) => Instr::Call(imm16(&mut next), Cond::Always),
+        (3,0,6) => Instr::AddImm(next()),
+        (3,1,6) => Instr::AddCarryImm(next()),
+        (3,2,6) => Instr::SubImm(next()),
+        (3,3,6) => Instr::SubCarryImm(next()),
+        (3,4,6) => Instr::AndImm(next()),
+        (3,5,6) => Instr::XorImm(next()),
+        (3,6,6) => Instr::OrImm(next()),
+        (3,7,6) => Instr::CompImm(next()),
+        (3,_,7) => Instr::Restart(y),
+        _ => Instr::Bad(opcode),
+    }
+}
+
+fn imm16<F: FnMut() -> u8>(next: &mut F) -> u16 {
+    let lo = next() as u16;
+    let hi = next() as u16;
+    hi << 8 | lo
+}
+
+// Encodes an instruction, the reverse of `decode`.
+pub fn encode(instr: &Instr) -> Vec<u8> {
+    let imm16 = |opcode: u8, value: u16| vec![opcode, value as u8, (value >> 8) as u8];
+    let cb = |operation: u8, y: u8, reg: Reg8| vec![0xcb, operation << 6 | y << 3 | reg.bits()];
+    match *instr {
+        Instr::Bad(opcode) => vec![opcode],
+        Instr::Nop => vec![0x00],
+        Instr::StoreSP(addr) => imm16(0x08, addr),
+        Instr::Stop => vec![0x10, 0x00],
+        Instr::JumpRelative(offset, Cond::Always) => vec![0x18, offset as u8],
+        Instr::JumpRelative(offset, cond) => vec![0x20 | cond.bits() << 3, offset as u8],
+        Instr::LoadImm16(reg, imm) => imm16(0x01 | reg.bits() << 4, imm),
+        Instr::AddHL(reg) => vec![0x09 | reg.bits() << 4],
+        Instr::StoreA(indirect) => vec![0x02 | indirect.bits() << 4],
+        Instr::LoadA(indirect) => vec![0x0a | indirect.bits() << 4],
+        Instr::Inc16(reg) => vec![0x03 | reg.bits() << 4],
+        Instr::Dec16(reg) => vec![0x0b | reg.bits() << 4],
+        Instr::Inc8(reg) => vec![0x04 | reg.bits() << 3],
+        Instr::Dec8(reg) => vec![0x05 | reg.bits() << 3],
+        Instr::LoadImm8(reg, imm) => vec![0x06 | reg.bits() << 3, imm],
+        Instr::RotateALeft => vec![0x07],
+        Instr::RotateARight => vec![0x0f],
+        Instr::RotateALeftCarry => vec![0x17],
+        Instr::RotateARightCarry => vec![0x1f],
+        Instr::DecimalAdjust => vec![0x27],
+        Instr::Complement => vec![0x2f],
+        Instr::SetCarry => vec![0x37],
+        Instr::ComplementCarry => vec![0x3f],
+        Instr::Halt => vec![0x76],
+        Instr::RegCopy(to, from) => vec![0x40 | to.bits() << 3 | from.bits()],
+        Instr::Add(reg) => vec![0x80 | reg.bits()],
+        Instr::AddCarry(reg) => vec![0x88 | reg.bits()],
+        Instr::Sub(reg) => vec![0x90 | reg.bits()],
+        Instr::SubCarry(reg) => vec![0x98 | reg.bits()],
+        Instr::And(reg) => vec![0xa0 | reg.bits()],
+        Instr::Xor(reg) => vec![0xa8 | reg.bits()],
+        Instr::Or(reg) => vec![0xb0 | reg.bits()],
+        Instr::Comp(reg) => vec![0xb8 | reg.bits()],
+        Instr::Ret(Cond::Always) => vec![0xc9],
+        Instr::Ret(cond) => vec![0xc0 | cond.bits() << 3],
+        Instr::StoreIO(imm) => vec![0xe0, imm],
+        Instr::StackAdjust(imm) => vec![0xe8, imm as u8],
+        Instr::LoadIO(imm) => vec![0xf0, imm],
+        Instr::LoadLocalAddr(imm) => vec![0xf8, imm as u8],
+        Instr::Pop(reg) => vec![0xc1 | reg.bits() << 4],
+        Instr::RetI => vec![0xd9],
+        Instr::JumpHL => vec![0xe9],
+        Instr::LoadStackHL => vec![0xf9],
+        Instr::Jump(addr, Cond::Always) => imm16(0xc3, addr),
+        Instr::Jump(addr, cond) => imm16(0xc2 | cond.bits() << 3, addr),
+        Instr::StoreIOC => vec![0xe2],
+        Instr::StoreGlobal(addr) => imm16(0xea, addr),
+        Instr::LoadIOC => vec![0xf2],
+        Instr::LoadGlobal(addr) => imm16(0xfa, addr),
+        Instr::DisableInterrupts => vec![0xf3],
+        Instr::EnableInterrupts => vec![0xfb],
+        Instr::Call(addr, Cond::Always) => imm16(0xcd, addr),
+        Instr::Call(addr, cond) => imm16(0xc4 | cond.bits() << 3, addr),
+        Instr::Push(reg) => vec![0xc5 | reg.bits() << 4],
+        Instr::AddImm(imm) => vec![0xc6, imm],
+        Instr::AddCarryImm(imm) => vec![0xce, imm],
+        Instr::SubImm(imm) => vec![0xd6, imm],
+        Instr::SubCarryImm(imm) => vec![0xde, imm],
+        Instr::AndImm(imm) => vec![0xe6, imm],
+        Instr::XorImm(imm) => vec![0xee, imm],
+        Instr::OrImm(imm) => vec![0xf6, imm],
+        Instr::CompImm(imm) => vec![0xfe, imm],
+        Instr::Restart(index) => vec![0xc7 | index << 3],
+        Instr::RotateLeft(reg) => cb(0, 0, reg),
+        Instr::RotateRight(reg) => cb(0, 1, reg),
+        Instr::RotateLeftCarry(reg) => cb(0, 2, reg),
+        Instr::RotateRightCarry(reg) => cb(0, 3, reg),
+        Instr::ShiftLeft(reg) => cb(0, 4, reg),
+        Instr::ShiftRightArith(reg) => cb(0, 5, reg),
+        Instr::SwapBytes(reg) => cb(0, 6, reg),
+        Instr::ShiftRightLogical(reg) => cb(0, 7, reg),
+        Instr::Bit(reg, bit) => cb(1, bit, reg),
+        Instr::Reset(reg, bit) => cb(2, bit, reg),
+        Instr::Set(reg, bit) => cb(3, bit, reg),
+    }
+}
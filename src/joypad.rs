@@ -12,8 +12,11 @@ impl Joypad {
             input_lines: 0
         }
     }
-    pub fn key_down(&mut self, button: Button) {
+    // Returns true if a selected input line went low.
+    pub fn key_down(&mut self, button: Button) -> bool {
+        let old_lines = self.read();
         self.button_state &= !button.value();
+        old_lines & !self.read() & 0x0f != 0
     }
     pub fn key_up(&mut self, button: Button) {
         self.button_state |= button.value();
@@ -0,0 +1,27 @@
+#[macro_use]
+extern crate bitflags;
+extern crate png;
+extern crate rustyline;
+extern crate sdl2;
+
+pub mod assembler;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod expr;
+pub mod gpu;
+pub mod instructions;
+pub mod joypad;
+pub mod link;
+pub mod printer;
+pub mod registers;
+pub mod serial;
+pub mod symbols;
+pub mod timer;
+pub mod trace;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+pub const SCREEN_BUFFER_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 3;
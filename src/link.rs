@@ -0,0 +1,50 @@
+use cpu::Cpu;
+use debugger::DebugState;
+use serial::Link;
+
+// An in-memory link cable between two Game Boys. The cable keeps the
+// two machines in lock-step: each call to `step` advances whichever
+// side is behind by one instruction, then carries any finished
+// transfer across to the other side.
+pub struct LinkCable {
+    left_cycles: u64,
+    right_cycles: u64,
+}
+
+impl Default for LinkCable {
+    fn default() -> Self { LinkCable::new() }
+}
+
+impl LinkCable {
+    pub fn new() -> Self {
+        LinkCable {
+            left_cycles: 0,
+            right_cycles: 0,
+        }
+    }
+    pub fn connect(&mut self, left: &mut Cpu, right: &mut Cpu) {
+        left.bus.connect_serial(Link::Cable);
+        right.bus.connect_serial(Link::Cable);
+        self.left_cycles = 0;
+        self.right_cycles = 0;
+    }
+    pub fn step(&mut self, left: &mut Cpu, right: &mut Cpu,
+                left_debug: &mut DebugState, right_debug: &mut DebugState) {
+        if self.left_cycles <= self.right_cycles {
+            self.left_cycles += left.step(left_debug) as u64;
+        } else {
+            self.right_cycles += right.step(right_debug) as u64;
+        }
+        LinkCable::transfer(left, right);
+        LinkCable::transfer(right, left);
+    }
+    pub fn cycles(&self) -> u64 {
+        ::std::cmp::min(self.left_cycles, self.right_cycles)
+    }
+    fn transfer(master: &mut Cpu, slave: &mut Cpu) {
+        if let Some(sent) = master.bus.serial_cable_transfer() {
+            let received = slave.bus.receive_serial_transfer(sent);
+            master.bus.complete_serial_transfer(received);
+        }
+    }
+}
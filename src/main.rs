@@ -1,54 +1,76 @@
 #[macro_use]
-extern crate bitflags;
-#[macro_use]
 extern crate clap;
-extern crate rustyline;
+extern crate gamebust;
 extern crate sdl2;
 
-use std::path::Path;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path,PathBuf};
 use std::time::{Duration, Instant};
 
-mod bus;
-mod cartridge;
-mod cpu;
-mod debugger;
-mod gpu;
-mod instructions;
-mod joypad;
-mod registers;
-mod serial;
-mod timer;
-
 use sdl2::event::Event;
 use sdl2::keyboard::Scancode;
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 
-use cpu::Cpu;
-use debugger::Debugger;
-use joypad::Button;
-
-const SCREEN_WIDTH: usize = 160;
-const SCREEN_HEIGHT: usize = 144;
-const SCREEN_BUFFER_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 3;
+use gamebust::cpu::Cpu;
+use gamebust::debugger::{DebugState,Debugger};
+use gamebust::disasm::{self,Syntax};
+use gamebust::joypad::Button;
+use gamebust::link::LinkCable;
+use gamebust::printer::Printer;
+use gamebust::serial::{Link,Sink};
+use gamebust::trace::{Trace,Trigger};
+use gamebust::{SCREEN_WIDTH,SCREEN_HEIGHT,SCREEN_BUFFER_SIZE};
 
 fn main() {
     let matches = clap_app!(gamebust =>
                             (version: "0.1")
                             (author: "Iain Ireland")
                             (about: "gameboy emulator")
+                            (@setting SubcommandsNegateReqs)
                             (@arg INPUT: +required "Sets the input file to use")
+                            (@arg LINK: --link +takes_value
+                             "Connects a second gameboy running this file by link cable")
+                            (@arg PRINTER: --printer +takes_value conflicts_with[LINK]
+                             "Connects a printer that saves its output to this directory")
+                            (@arg SERIAL: --serial +takes_value
+                             "Copies serial output to stdout (default), a file, or none")
+                            (@arg STRICT: --strict
+                             "Reports reads and writes to unmapped addresses")
+                            (@arg TRACE: --trace +takes_value
+                             "Logs each instruction to this file in gameboy-doctor format")
+                            (@arg TRACE_START: --("trace-start") +takes_value requires[TRACE]
+                             "Starts tracing at a PC (0x0100) or instruction count (5000)")
+                            (@arg TRACE_STOP: --("trace-stop") +takes_value requires[TRACE]
+                             "Stops tracing at a PC (0x0100) or instruction count (5000)")
+                            (@subcommand disasm =>
+                             (about: "Disassembles part of a ROM")
+                             (@arg ROM: +required "The ROM file to disassemble")
+                             (@arg BANK: --bank +takes_value "The bank mapped at 0x4000, in hex (default 1)")
+                             (@arg FROM: --from +takes_value "The address to start at, in hex")
+                             (@arg TO: --to +takes_value "The last address to list, in hex")
+                             (@arg RGBDS: --rgbds "Prints source that RGBDS can assemble")
+                             (@arg PROJECT: --project +takes_value
+                              "Traces code from the entry points and writes an RGBDS project to this directory"))
 
     ).get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("disasm") {
+        disasm(matches);
+        return;
+    }
+
     let input_file = matches.value_of("INPUT").unwrap();
+    let link_file = matches.value_of("LINK");
 
     let scale = 5;
+    let num_screens = if link_file.is_some() { 2 } else { 1 };
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem.window("Gamebust",
-                                        SCREEN_WIDTH as u32 * scale,
+                                        SCREEN_WIDTH as u32 * scale * num_screens,
                                         SCREEN_HEIGHT as u32 * scale)
         .position_centered()
         .opengl()
@@ -60,15 +82,49 @@ fn main() {
         create_texture_streaming(sdl2::pixels::PixelFormatEnum::RGB24,
                                  SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32).unwrap();
     let screen_rect = Rect::new(0, 0, SCREEN_WIDTH as u32 * scale, SCREEN_HEIGHT as u32 * scale);
+    let partner_rect = Rect::new(SCREEN_WIDTH as i32 * scale as i32, 0,
+                                 SCREEN_WIDTH as u32 * scale, SCREEN_HEIGHT as u32 * scale);
     canvas.set_draw_color(Color::RGB(0,0,0));
     canvas.clear();
 
     let mut events = sdl_context.event_pump().unwrap();
     let mut cpu = Cpu::new(Path::new(&input_file));
     let mut debugger = Debugger::new();
+    let sym_file = Path::new(&input_file).with_extension("sym");
+    if sym_file.exists() {
+        match debugger.load_symbols(&sym_file) {
+            Ok(count) => println!("Loaded {} symbols from {}", count, sym_file.display()),
+            Err(err) => println!("Couldn't read {}: {}", sym_file.display(), err),
+        }
+    }
     let mut frame_start = Instant::now();
     let mut screen_buffer = [0; SCREEN_BUFFER_SIZE];
 
+    let mut partner = link_file.map(|file| Cpu::new(Path::new(file)));
+    let mut partner_debug = DebugState::new();
+    let mut cable = LinkCable::new();
+    if let Some(ref mut partner) = partner {
+        cable.connect(&mut cpu, partner);
+    }
+    let sink = match matches.value_of("SERIAL").unwrap_or("stdout") {
+        "stdout" => Sink::Stdout,
+        "none" => Sink::None,
+        file => Sink::File(File::create(file).expect("Couldn't create serial output file")),
+    };
+    cpu.bus.set_serial_sink(sink);
+    cpu.bus.set_strict(matches.is_present("STRICT"));
+    if let Some(file) = matches.value_of("TRACE") {
+        let trigger = |name| matches.value_of(name).map(|value| {
+            Trigger::parse(value).expect("Trace triggers are a PC (0x0100) or an instruction count")
+        });
+        let trace = Trace::new(Path::new(file), trigger("TRACE_START"), trigger("TRACE_STOP"))
+            .expect("Couldn't create trace file");
+        cpu.set_trace(Some(trace));
+    }
+    if let Some(dir) = matches.value_of("PRINTER") {
+        cpu.bus.connect_serial(Link::Printer(Printer::new(PathBuf::from(dir))));
+    }
+
     'eventloop: loop {
 
         for event in events.poll_iter() {
@@ -91,10 +147,20 @@ fn main() {
         }
 
         if debugger.is_paused() {
-            debugger.debug(&cpu);
+            debugger.debug(&mut cpu);
         }
 
-        cpu.step(debugger.get_state());
+        match partner {
+            Some(ref mut partner) => {
+                cable.step(&mut cpu, partner, debugger.get_state(), &mut partner_debug);
+                if partner.needs_redraw() {
+                    partner.fill_screen_buffer(&mut screen_buffer);
+                    screen_texture.update(None, &screen_buffer, SCREEN_WIDTH * 3).unwrap();
+                    canvas.copy(&screen_texture, None, partner_rect).unwrap();
+                }
+            },
+            None => { cpu.step(debugger.get_state()); }
+        }
         if cpu.needs_redraw() {
             const MICROS_PER_FRAME: u64 = 1_000_000 / 60;
             cpu.fill_screen_buffer(&mut screen_buffer);
@@ -110,3 +176,36 @@ fn main() {
         }
     }
 }
+
+fn parse_addr(text: &str) -> u16 {
+    let digits = text.trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).expect("Addresses are hex numbers")
+}
+
+fn disasm(matches: &clap::ArgMatches) {
+    let mut rom = Vec::new();
+    let path = matches.value_of("ROM").unwrap();
+    File::open(path).and_then(|mut file| file.read_to_end(&mut rom))
+        .expect("Couldn't read ROM");
+    if let Some(dir) = matches.value_of("PROJECT") {
+        match disasm::write_project(&rom, Path::new(dir)) {
+            Ok(map) => println!("Wrote {} ({} of {} bytes are code)", dir, map.code_bytes(), rom.len()),
+            Err(e) => eprintln!("Couldn't write project: {}", e),
+        }
+        return;
+    }
+    // Banks are hex, like the bank_XX.asm files and the debugger.
+    let bank = matches.value_of("BANK")
+        .map(|bank| usize::from_str_radix(bank.trim_start_matches("0x"), 16).expect("Banks are hex numbers"))
+        .unwrap_or(1);
+    let default_from = if matches.is_present("BANK") && bank != 0 { 0x4000 } else { 0 };
+    let from = matches.value_of("FROM").map(parse_addr).unwrap_or(default_from);
+    let to = matches.value_of("TO").map(parse_addr).unwrap_or(0x7fff);
+    let syntax = if matches.is_present("RGBDS") { Syntax::Rgbds } else { Syntax::Native };
+
+    let stdout = ::std::io::stdout();
+    let result = disasm::disassemble(&mut stdout.lock(), &rom, bank, from, to, syntax);
+    if let Err(e) = result {
+        eprintln!("Couldn't write disassembly: {}", e);
+    }
+}
@@ -0,0 +1,231 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path,PathBuf};
+
+use png::HasParameters;
+
+const TILES_PER_ROW: usize = 20;
+const BYTES_PER_TILE: usize = 16;
+const IMAGE_WIDTH: usize = TILES_PER_ROW * 8;
+// The printer has room for nine bands of two tile rows each.
+const BUFFER_SIZE: usize = 9 * 2 * TILES_PER_ROW * BYTES_PER_TILE;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0f;
+
+bitflags! {
+    struct Status: u8 {
+        const CHECKSUM_ERROR = 0b0000_0001;
+        const BUSY           = 0b0000_0010;
+        const IMAGE_FULL     = 0b0000_0100;
+        const UNPROCESSED    = 0b0000_1000;
+    }
+}
+
+#[derive(Copy,Clone,Debug)]
+enum State {
+    Magic1, Magic2, Command, Compression, LengthLo, LengthHi,
+    Data, ChecksumLo, ChecksumHi, Alive, Status,
+}
+
+// A Game Boy Printer hanging off the serial port. Packets look like
+//   88 33 <command> <compression> <length:2> <data...> <checksum:2> 00 00
+// and the printer answers the final two bytes with 0x81 and its status.
+// Each print command writes the buffered image to a numbered PNG.
+pub struct Printer {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    packet: Vec<u8>,
+    checksum: u16,
+    expected_checksum: u16,
+    status: Status,
+    image: Vec<u8>,
+    output_dir: PathBuf,
+    num_prints: u32,
+}
+
+impl Printer {
+    pub fn new(output_dir: PathBuf) -> Self {
+        Printer {
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet: Vec::new(),
+            checksum: 0,
+            expected_checksum: 0,
+            status: Status::empty(),
+            image: Vec::new(),
+            output_dir,
+            num_prints: 0,
+        }
+    }
+    pub fn exchange(&mut self, value: u8) -> u8 {
+        let mut response = 0;
+        self.state = match self.state {
+            State::Magic1 => if value == 0x88 { State::Magic2 } else { State::Magic1 },
+            State::Magic2 => if value == 0x33 { State::Command } else { State::Magic1 },
+            State::Command => {
+                self.command = value;
+                self.checksum = value as u16;
+                State::Compression
+            },
+            State::Compression => {
+                self.compressed = value & 0x1 != 0;
+                self.checksum = self.checksum.wrapping_add(value as u16);
+                State::LengthLo
+            },
+            State::LengthLo => {
+                self.length = value as u16;
+                self.checksum = self.checksum.wrapping_add(value as u16);
+                State::LengthHi
+            },
+            State::LengthHi => {
+                self.length |= (value as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(value as u16);
+                self.packet.clear();
+                if self.length == 0 { State::ChecksumLo } else { State::Data }
+            },
+            State::Data => {
+                self.packet.push(value);
+                self.checksum = self.checksum.wrapping_add(value as u16);
+                if self.packet.len() == self.length as usize { State::ChecksumLo } else { State::Data }
+            },
+            State::ChecksumLo => {
+                self.expected_checksum = value as u16;
+                State::ChecksumHi
+            },
+            State::ChecksumHi => {
+                self.expected_checksum |= (value as u16) << 8;
+                State::Alive
+            },
+            State::Alive => {
+                response = 0x81;
+                State::Status
+            },
+            State::Status => {
+                response = self.process_packet();
+                State::Magic1
+            },
+        };
+        response
+    }
+    pub fn num_prints(&self) -> u32 {
+        self.num_prints
+    }
+    fn process_packet(&mut self) -> u8 {
+        if self.checksum != self.expected_checksum {
+            self.status.insert(Status::CHECKSUM_ERROR);
+            return self.status.bits();
+        }
+        self.status.remove(Status::CHECKSUM_ERROR);
+        match self.command {
+            COMMAND_INIT => {
+                self.image.clear();
+                self.status = Status::empty();
+            },
+            COMMAND_DATA => {
+                let data = if self.compressed {
+                    decompress(&self.packet)
+                } else {
+                    self.packet.clone()
+                };
+                let space = BUFFER_SIZE - self.image.len();
+                self.image.extend(data.into_iter().take(space));
+                if !self.image.is_empty() {
+                    self.status.insert(Status::UNPROCESSED);
+                }
+                if self.image.len() == BUFFER_SIZE {
+                    self.status.insert(Status::IMAGE_FULL);
+                }
+            },
+            COMMAND_PRINT => {
+                if self.packet.len() == 4 {
+                    let (sheets, margins, palette) = (self.packet[0], self.packet[1], self.packet[2]);
+                    if sheets > 0 {
+                        self.print(margins, palette);
+                    }
+                }
+                self.image.clear();
+                self.status = Status::BUSY;
+            },
+            COMMAND_STATUS => {
+                // Report busy once after a print, then finish printing.
+                let result = self.status.bits();
+                self.status.remove(Status::BUSY);
+                return result;
+            },
+            _ => {}
+        }
+        self.status.bits()
+    }
+    fn print(&mut self, margins: u8, palette: u8) {
+        const BRIGHTNESS: [u8; 4] = [0xff, 0xaa, 0x55, 0x00];
+
+        // Each unit of margin feeds a blank tile row.
+        let margin_before = (margins >> 4) as usize * 8;
+        let margin_after = (margins & 0xf) as usize * 8;
+        let palette = if palette == 0 { 0xe4 } else { palette };
+
+        let tile_rows = self.image.len() / (TILES_PER_ROW * BYTES_PER_TILE);
+        let height = margin_before + tile_rows * 8 + margin_after;
+        let mut pixels = vec![BRIGHTNESS[0]; IMAGE_WIDTH * height];
+
+        for (i, tile) in self.image.chunks(BYTES_PER_TILE).take(tile_rows * TILES_PER_ROW).enumerate() {
+            let tile_x = (i % TILES_PER_ROW) * 8;
+            let tile_y = margin_before + (i / TILES_PER_ROW) * 8;
+            for line in 0..8 {
+                let low = tile[line * 2];
+                let high = tile[line * 2 + 1];
+                for j in 0..8 {
+                    let index = ((high >> (7 - j)) & 1) << 1 | ((low >> (7 - j)) & 1);
+                    let shade = (palette >> (index * 2)) & 0x3;
+                    pixels[(tile_y + line) * IMAGE_WIDTH + tile_x + j] = BRIGHTNESS[shade as usize];
+                }
+            }
+        }
+
+        let path = self.output_dir.join(format!("print-{:03}.png", self.num_prints));
+        self.num_prints += 1;
+        if let Err(e) = write_png(&path, &pixels, IMAGE_WIDTH as u32, height as u32) {
+            eprintln!("Printer: could not write {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            // Run of a single repeated byte.
+            let length = (control & 0x7f) as usize + 2;
+            if let Some(&value) = data.get(i) {
+                result.resize(result.len() + length, value);
+            }
+            i += 1;
+        } else {
+            // Literal bytes copied straight through.
+            let length = control as usize + 1;
+            let end = ::std::cmp::min(i + length, data.len());
+            result.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    result
+}
+
+fn write_png(path: &Path, pixels: &[u8], width: u32, height: u32) -> ::std::io::Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = ::png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set(::png::ColorType::Grayscale).set(::png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    Ok(())
+}
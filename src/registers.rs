@@ -18,6 +18,18 @@ impl Reg8 {
             _ => unreachable!("Invalid reg8 field")
         }
     }
+    pub fn bits(&self) -> u8 {
+        match self {
+            Reg8::B => 0,
+            Reg8::C => 1,
+            Reg8::D => 2,
+            Reg8::E => 3,
+            Reg8::H => 4,
+            Reg8::L => 5,
+            Reg8::HL => 6,
+            Reg8::A => 7,
+        }
+    }
 }
 impl fmt::Display for Reg8 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -49,6 +61,15 @@ impl Reg16 {
             _ => unreachable!("Invalid reg16 field")
         }
     }
+    // The register pair's position in an opcode; SP and AF share 3.
+    pub fn bits(&self) -> u8 {
+        match self {
+            Reg16::BC => 0,
+            Reg16::DE => 1,
+            Reg16::HL => 2,
+            Reg16::SP | Reg16::AF => 3,
+        }
+    }
 }
 impl fmt::Display for Reg16 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -77,6 +98,14 @@ impl Indirect {
             _ => unreachable!("Invalid indirect reg field")
         }
     }
+    pub fn bits(&self) -> u8 {
+        match self {
+            Indirect::BC => 0,
+            Indirect::DE => 1,
+            Indirect::HLPlus => 2,
+            Indirect::HLMinus => 3,
+        }
+    }
 }
 impl fmt::Display for Indirect {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
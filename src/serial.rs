@@ -1,11 +1,58 @@
+use std::fs::File;
+use std::io::Write;
+
+use cpu::Interrupt;
+use printer::Printer;
+
+// The internal clock shifts one bit every 512 cycles (8192 Hz).
+const TRANSFER_CYCLES: i32 = 8 * 512;
+
 enum Clock {
     External, Internal
 }
 
+pub enum Link {
+    Disconnected,
+    Cable,
+    Printer(Printer),
+}
+
+// Every byte the gameboy starts sending is also copied to the sink.
+// Test ROMs use this to report their results.
+pub enum Sink {
+    None,
+    Stdout,
+    File(File),
+    Buffer(Vec<u8>),
+}
+
+impl Sink {
+    fn write(&mut self, value: u8) {
+        let result = match self {
+            Sink::None => Ok(()),
+            Sink::Stdout => { print!("{}", value as char); Ok(()) },
+            Sink::File(file) => file.write_all(&[value]),
+            Sink::Buffer(buffer) => { buffer.push(value); Ok(()) },
+        };
+        if let Err(e) = result {
+            eprintln!("Serial: could not write output: {}", e);
+        }
+    }
+    pub fn contents(&self) -> &[u8] {
+        match self {
+            Sink::Buffer(buffer) => buffer,
+            _ => &[]
+        }
+    }
+}
+
 pub struct Serial {
     data: u8,
     start: bool,
-    clock: Clock
+    clock: Clock,
+    link: Link,
+    sink: Sink,
+    cycles_left: i32,
 }
 
 impl Serial {
@@ -13,8 +60,69 @@ impl Serial {
         Serial {
             data: 0,
             start: false,
-            clock: Clock::External
+            clock: Clock::External,
+            link: Link::Disconnected,
+            sink: Sink::None,
+            cycles_left: 0,
+        }
+    }
+    pub fn connect(&mut self, link: Link) {
+        self.link = link;
+    }
+    pub fn set_sink(&mut self, sink: Sink) {
+        self.sink = sink;
+    }
+    pub fn get_sink(&self) -> &Sink {
+        &self.sink
+    }
+    pub fn update(&mut self, cycles: u32, irq: &mut Interrupt) {
+        if !self.start || self.cycles_left <= 0 {
+            return;
+        }
+        if let Clock::External = self.clock {
+            return;
+        }
+        self.cycles_left -= cycles as i32;
+        if self.cycles_left <= 0 {
+            let received = match self.link {
+                // Nobody is driving the line, so we shift in ones.
+                Link::Disconnected => Some(0xff),
+                // The cable completes the transfer once it sees it.
+                Link::Cable => None,
+                Link::Printer(ref mut printer) => Some(printer.exchange(self.data)),
+            };
+            if let Some(received) = received {
+                self.complete_transfer(received, irq);
+            }
+        }
+    }
+    // Returns the outgoing byte once a transfer clocked by this side
+    // has shifted out all eight bits and is waiting on the cable.
+    pub fn cable_transfer(&self) -> Option<u8> {
+        match (&self.link, &self.clock) {
+            (Link::Cable, Clock::Internal) if self.start && self.cycles_left <= 0 =>
+                Some(self.data),
+            _ => None
+        }
+    }
+    pub fn complete_transfer(&mut self, received: u8, irq: &mut Interrupt) {
+        self.data = received;
+        self.start = false;
+        irq.insert(Interrupt::SERIAL);
+    }
+    // The other side clocked a byte across the cable. Returns the byte
+    // shifted out of this side in exchange.
+    pub fn receive_transfer(&mut self, received: u8, irq: &mut Interrupt) -> u8 {
+        if let Clock::Internal = self.clock {
+            return 0xff;
+        }
+        let sent = self.data;
+        self.data = received;
+        if self.start {
+            self.start = false;
+            irq.insert(Interrupt::SERIAL);
         }
+        sent
     }
     pub fn get_transfer(&self) -> u8 {
         self.data
@@ -30,8 +138,10 @@ impl Serial {
     }
     pub fn set_control(&mut self, value: u8) {
         if value & 0x80 != 0 {
-            print!("{}", self.data as char);
+            self.sink.write(self.data);
         }
+        self.start = value & 0x80 != 0;
         self.clock = if value & 0x01 == 0 { Clock::External } else { Clock::Internal };
+        self.cycles_left = TRANSFER_CYCLES;
     }
 }
@@ -0,0 +1,95 @@
+use std::collections::{BTreeMap,HashMap};
+use std::fs::File;
+use std::io::{self,Read};
+use std::path::Path;
+
+// Labels from an RGBDS or no$gmb .sym file, where each line is
+//   BB:AAAA Name
+// Banks only tell apart addresses in switchable ROM; everything else
+// is treated as bank 0.
+pub struct Symbols {
+    by_name: HashMap<String, (usize, u16)>,
+    by_addr: BTreeMap<(usize, u16), String>,
+}
+
+// Where one area of memory ends, so a label isn't used for an address
+// past the end of the area it's in.
+const REGION_ENDS: [u32; 9] = [0x4000, 0x8000, 0xa000, 0xc000, 0xe000, 0xfe00, 0xff00, 0xff80, 0x10000];
+
+fn region(addr: u16) -> usize {
+    REGION_ENDS.iter().position(|&end| (addr as u32) < end).unwrap()
+}
+
+fn symbol_bank(bank: usize, addr: u16) -> usize {
+    if region(addr) == 1 { bank } else { 0 }
+}
+
+impl Default for Symbols {
+    fn default() -> Self { Symbols::new() }
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols {
+            by_name: HashMap::new(),
+            by_addr: BTreeMap::new(),
+        }
+    }
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        Ok(Symbols::parse(&text))
+    }
+    // Lines that aren't symbols, such as comments, are skipped.
+    pub fn parse(text: &str) -> Self {
+        let mut symbols = Symbols::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap().trim();
+            let mut words = line.split_whitespace();
+            let (location, name) = match (words.next(), words.next()) {
+                (Some(location), Some(name)) => (location, name),
+                _ => continue,
+            };
+            let mut parts = location.splitn(2, ':');
+            let bank = parts.next().and_then(|bank| usize::from_str_radix(bank, 16).ok());
+            let addr = parts.next().and_then(|addr| u16::from_str_radix(addr, 16).ok());
+            if let (Some(bank), Some(addr)) = (bank, addr) {
+                symbols.insert(bank, addr, name);
+            }
+        }
+        symbols
+    }
+    pub fn insert(&mut self, bank: usize, addr: u16, name: &str) {
+        let bank = symbol_bank(bank, addr);
+        self.by_name.insert(name.to_string(), (bank, addr));
+        // Keep the first label at each address.
+        self.by_addr.entry((bank, addr)).or_insert_with(|| name.to_string());
+    }
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+    // The bank and address of a label.
+    pub fn lookup(&self, name: &str) -> Option<(usize, u16)> {
+        self.by_name.get(name).cloned()
+    }
+    // The label at exactly this address.
+    pub fn label(&self, bank: usize, addr: u16) -> Option<&str> {
+        self.by_addr.get(&(symbol_bank(bank, addr), addr)).map(|name| &name[..])
+    }
+    // The closest label at or before an address, with an offset if it
+    // isn't exact, e.g. "Main.loop+3".
+    pub fn describe(&self, bank: usize, addr: u16) -> Option<String> {
+        let bank = symbol_bank(bank, addr);
+        let (&(label_bank, label_addr), name) = self.by_addr.range(..=(bank, addr)).next_back()?;
+        if label_bank != bank || region(label_addr) != region(addr) {
+            return None;
+        }
+        Some(match addr - label_addr {
+            0 => name.clone(),
+            offset => format!("{}+{:#x}", name, offset),
+        })
+    }
+}
@@ -1,51 +1,96 @@
 use cpu::Interrupt;
 
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+enum Reload {
+    None,
+    // TIMA overflowed on the last cycle and reads as zero.
+    Pending,
+    // TIMA was loaded from TMA on the last cycle.
+    Reloading,
+}
+
+// DIV and TIMA are both driven by a 16-bit counter that ticks every
+// cycle. DIV is its upper byte; TIMA ticks whenever the counter bit
+// selected by TAC (ANDed with the enable bit) has a falling edge. This
+// means resetting DIV or changing TAC can also tick TIMA.
 pub struct Timer {
-    divider: u16,
+    system_counter: u16,
     counter: u8,
     modulo: u8,
     tac_enabled: bool,
     frequency: u8,
-    cycles: u32
+    reload: Reload,
 }
 
 impl Timer {
     pub fn new() -> Self {
         Timer {
-            divider: 0,
+            system_counter: 0,
             counter: 0,
             modulo: 0,
             tac_enabled: false,
             frequency: 0,
-            cycles: 0,
+            reload: Reload::None,
         }
     }
     pub fn update(&mut self, cycles: u32, irq: &mut Interrupt) {
-        self.divider = self.divider.wrapping_add(cycles as u16);
+        for _ in 0..cycles / 4 {
+            self.tick(irq);
+        }
+    }
+    // Advance by one machine cycle.
+    fn tick(&mut self, irq: &mut Interrupt) {
+        match self.reload {
+            Reload::Pending => {
+                self.counter = self.modulo;
+                irq.insert(Interrupt::TIMER);
+                self.reload = Reload::Reloading;
+            },
+            Reload::Reloading => self.reload = Reload::None,
+            Reload::None => {}
+        }
 
-        if self.tac_enabled {
-            self.cycles += cycles;
-            let target = match self.frequency {
-                0 => 1024, 1 => 16, 2 => 64, 3 => 256,
-                _ => unreachable!("Invalid timer frequency")
-            };
-            if self.cycles >= target {
-                let (c, carry) = self.counter.overflowing_add(1);
-                self.counter = c;
-                if carry {
-                    self.counter = self.modulo;
-                    irq.insert(Interrupt::TIMER);
-                }
-            }
+        let old_signal = self.signal();
+        self.system_counter = self.system_counter.wrapping_add(4);
+        if old_signal && !self.signal() {
+            self.increment();
+        }
+    }
+    #[inline(always)]
+    fn signal(&self) -> bool {
+        let bit = match self.frequency {
+            0 => 9, 1 => 3, 2 => 5, 3 => 7,
+            _ => unreachable!("Invalid timer frequency")
+        };
+        self.tac_enabled && self.system_counter & (1 << bit) != 0
+    }
+    #[inline(always)]
+    fn increment(&mut self) {
+        let (c, carry) = self.counter.overflowing_add(1);
+        self.counter = c;
+        if carry {
+            self.reload = Reload::Pending;
         }
     }
     #[inline(always)]
     pub fn get_divider(&self) -> u8 {
-        (self.divider >> 8) as u8
+        (self.system_counter >> 8) as u8
+    }
+    // The whole counter DIV is the top of.
+    pub fn get_system_counter(&self) -> u16 {
+        self.system_counter
+    }
+    // Whether TIMA has overflowed and is about to be reloaded.
+    pub fn reload_pending(&self) -> bool {
+        self.reload != Reload::None
     }
     #[inline(always)]
     pub fn reset_divider(&mut self) {
-        self.divider = 0;
+        let old_signal = self.signal();
+        self.system_counter = 0;
+        if old_signal {
+            self.increment();
+        }
     }
     #[inline(always)]
     pub fn get_counter(&self) -> u8 {
@@ -53,7 +98,16 @@ impl Timer {
     }
     #[inline(always)]
     pub fn set_counter(&mut self, value: u8) {
-        self.counter = value;
+        match self.reload {
+            // Writing during the delay cancels the reload and interrupt.
+            Reload::Pending => {
+                self.counter = value;
+                self.reload = Reload::None;
+            },
+            // Writing on the reload cycle is overridden by TMA.
+            Reload::Reloading => {},
+            Reload::None => self.counter = value,
+        }
     }
     #[inline(always)]
     pub fn get_modulo(&self) -> u8 {
@@ -62,6 +116,9 @@ impl Timer {
     #[inline(always)]
     pub fn set_modulo(&mut self, value: u8) {
         self.modulo = value;
+        if self.reload == Reload::Reloading {
+            self.counter = value;
+        }
     }
     #[inline(always)]
     pub fn get_control(&self) -> u8 {
@@ -71,7 +128,11 @@ impl Timer {
     }
     #[inline(always)]
     pub fn set_control(&mut self, value: u8) {
+        let old_signal = self.signal();
         self.tac_enabled = value & (1 << 2) != 0;
         self.frequency = value & 0x3;
+        if old_signal && !self.signal() {
+            self.increment();
+        }
     }
 }
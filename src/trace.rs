@@ -0,0 +1,95 @@
+use std::fs::File;
+use std::io::{BufWriter,Write};
+use std::path::Path;
+
+use registers::{Registers,Reg8,Reg16};
+
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum Trigger {
+    Pc(u16),
+    // Number of instructions executed since tracing was set up.
+    Instructions(u64),
+}
+
+impl Trigger {
+    // "0x0100" is a PC; a plain number is an instruction count.
+    pub fn parse(text: &str) -> Option<Trigger> {
+        if let Some(hex) = text.strip_prefix("0x") {
+            u16::from_str_radix(hex, 16).ok().map(Trigger::Pc)
+        } else {
+            text.parse().ok().map(Trigger::Instructions)
+        }
+    }
+    fn hit(&self, pc: u16, instructions: u64) -> bool {
+        match *self {
+            Trigger::Pc(addr) => pc == addr,
+            Trigger::Instructions(count) => instructions >= count,
+        }
+    }
+}
+
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+enum State {
+    Waiting, Tracing, Done
+}
+
+// Writes one line per instruction in the format used by
+// gameboy-doctor, before the instruction executes:
+//   A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+pub struct Trace {
+    out: BufWriter<File>,
+    start: Option<Trigger>,
+    stop: Option<Trigger>,
+    state: State,
+    instructions: u64,
+}
+
+impl Trace {
+    pub fn new(path: &Path, start: Option<Trigger>, stop: Option<Trigger>) -> ::std::io::Result<Self> {
+        Ok(Trace {
+            out: BufWriter::new(File::create(path)?),
+            start,
+            stop,
+            state: State::Waiting,
+            instructions: 0,
+        })
+    }
+    pub fn record(&mut self, reg: &Registers, pcmem: [u8; 4]) {
+        let count = self.instructions;
+        self.instructions += 1;
+        if self.state == State::Waiting {
+            match self.start {
+                Some(trigger) if !trigger.hit(reg.pc, count) => return,
+                _ => self.state = State::Tracing,
+            }
+        }
+        if self.state == State::Done {
+            return;
+        }
+        if let Some(trigger) = self.stop {
+            if trigger.hit(reg.pc, count) {
+                self.finish();
+                return;
+            }
+        }
+        let result = writeln!(self.out,
+                              "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} \
+                               SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+                              reg.r8(Reg8::A), reg.r16(Reg16::AF) as u8,
+                              reg.r8(Reg8::B), reg.r8(Reg8::C),
+                              reg.r8(Reg8::D), reg.r8(Reg8::E),
+                              reg.r8(Reg8::H), reg.r8(Reg8::L),
+                              reg.sp, reg.pc,
+                              pcmem[0], pcmem[1], pcmem[2], pcmem[3]);
+        if let Err(e) = result {
+            eprintln!("Trace: could not write: {}", e);
+            self.state = State::Done;
+        }
+    }
+    fn finish(&mut self) {
+        self.state = State::Done;
+        if let Err(e) = self.out.flush() {
+            eprintln!("Trace: could not write: {}", e);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+extern crate gamebust;
+
+use gamebust::assembler::{assemble,parse};
+use gamebust::instructions::{Instr,decode,encode};
+
+// Every opcode, with a few operand values, goes through Display, the
+// parser and the encoder and comes back as the same bytes.
+#[test]
+fn round_trips_all_opcodes() {
+    let operands: [[u8; 2]; 4] = [[0x00, 0x00], [0x34, 0x12], [0xfe, 0xff], [0x80, 0x7f]];
+    let mut checked = 0;
+    for opcode in 0..0x100 {
+        for prefix in &[None, Some(0xcb)] {
+            for operand in operands.iter() {
+                let bytes: Vec<u8> = match *prefix {
+                    Some(prefix) if opcode != 0xcb => vec![prefix, opcode as u8],
+                    Some(_) => continue,
+                    None => vec![opcode as u8, operand[0], operand[1]],
+                };
+                let (instr, length) = decode(&bytes).unwrap();
+                if let Instr::Bad(_) = instr {
+                    continue;
+                }
+                let text = instr.to_string();
+                assert_eq!(parse(&text), Ok(instr), "{}", text);
+                let expected = match instr {
+                    // STOP's padding byte always assembles as zero.
+                    Instr::Stop => vec![0x10, 0x00],
+                    _ => bytes[..length].to_vec(),
+                };
+                assert_eq!(encode(&instr), expected, "{}", text);
+                checked += 1;
+            }
+        }
+    }
+    assert_eq!(checked, (256 - 11 - 1) * 4 + 256 * 4);
+}
+
+#[test]
+fn accepts_other_spellings() {
+    assert_eq!(assemble("ld a, ($ff00+$44)"), Ok(vec![0xf0, 0x44]));
+    assert_eq!(assemble("jr c,-2"), Ok(vec![0x38, 0xfe]));
+    assert_eq!(assemble("cp a,0x10"), Ok(vec![0xfe, 0x10]));
+    assert_eq!(assemble("ld (hli),a"), Ok(vec![0x22]));
+    assert_eq!(assemble("jp hl"), Ok(vec![0xe9]));
+}
+
+#[test]
+fn rejects_bad_input() {
+    assert!(assemble("LD (HL),(HL)").is_err());
+    assert!(assemble("LD A,256").is_err());
+    assert!(assemble("JR 200").is_err());
+    assert!(assemble("RST 0x09").is_err());
+    assert!(assemble("PUSH SP").is_err());
+    assert!(assemble("FOO A").is_err());
+}
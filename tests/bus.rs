@@ -0,0 +1,145 @@
+extern crate gamebust;
+
+mod common;
+
+use gamebust::bus::{Bus,MemoryBus};
+use gamebust::cpu::Interrupt;
+
+use common::{bus_with,rom_with};
+
+// Ticks the bus until OAM DMA has copied its first byte.
+fn start_dma(bus: &mut Bus, page: u8) {
+    bus.w8(0xff46, page);
+    while !bus.dma().is_active() {
+        bus.update(4);
+    }
+}
+
+#[test]
+fn dma_copies_to_oam() {
+    let mut bus = bus_with(rom_with(&[]));
+    for i in 0..0xa0 {
+        bus.w8(0xc000 + i, i as u8 ^ 0x5a);
+    }
+    start_dma(&mut bus, 0xc0);
+    // The CPU sees the byte being copied, and OAM is locked.
+    assert_eq!(bus.r8(0xd000), 0x5a);
+    assert_eq!(bus.r8(0xfe00), 0xff);
+    while bus.dma().is_active() {
+        bus.update(4);
+    }
+    for i in 0..0xa0 {
+        assert_eq!(bus.r8(0xfe00 + i), i as u8 ^ 0x5a);
+    }
+}
+
+// The debugger shouldn't see what the CPU would during DMA.
+#[test]
+fn peeks_ignore_dma_conflicts() {
+    let mut bus = bus_with(rom_with(&[]));
+    bus.w8(0xc000, 0x42);
+    bus.w8(0xc123, 0x43);
+    start_dma(&mut bus, 0xc0);
+    assert_eq!(bus.r8(0xc123), 0x42);
+    assert_eq!(bus.peek(0xc123), 0x43);
+    assert_eq!(bus.peek(0xfe00), 0x42);
+}
+
+#[test]
+fn banked_peeks_ignore_dma_conflicts() {
+    let mut bus = bus_with(rom_with(&[]));
+    bus.w8(0xc000, 0x42);
+    bus.w8(0xc123, 0x43);
+    start_dma(&mut bus, 0xc0);
+    assert_eq!(bus.peek_bank(0, 0xc123), 0x43);
+    assert_eq!(bus.peek_bank(1, 0xfe00), 0x42);
+}
+
+#[test]
+fn patches_ignore_dma_conflicts() {
+    let mut bus = bus_with(rom_with(&[]));
+    start_dma(&mut bus, 0xc0);
+    bus.w8(0xc000, 0x42);
+    assert_eq!(bus.peek(0xc000), 0);
+    bus.patch(0xc000, 0x42);
+    bus.patch(0x0150, 0x43);
+    assert_eq!(bus.peek(0xc000), 0x42);
+    assert_eq!(bus.peek(0x0150), 0x43);
+}
+
+#[test]
+fn open_bus_values() {
+    let mut bus = bus_with(rom_with(&[]));
+    for &addr in [0xff03, 0xff08, 0xff4c, 0xff51, 0xff7f, 0xa000, 0xbfff].iter() {
+        assert_eq!(bus.r8(addr), 0xff, "{:04x}", addr);
+    }
+    // Unused bits in registers read as 1.
+    assert_eq!(bus.r8(0xff0f), 0xe0);
+    assert_eq!(bus.r8(0xff07), 0xf8);
+    assert_eq!(bus.r8(0xff10), 0x80);
+    assert_eq!(bus.r8(0xff41) & 0x80, 0x80);
+    // The unusable area after OAM reads as 0 until the PPU locks OAM.
+    assert_eq!(bus.r8(0xfea0), 0);
+    bus.w8(0xff40, 0x80);
+    assert_eq!(bus.r8(0xfea0), 0xff);
+    // Echo RAM mirrors work RAM.
+    bus.w8(0xc010, 0x42);
+    assert_eq!(bus.r8(0xe010), 0x42);
+}
+
+#[test]
+fn strict_mode() {
+    let mut bus = bus_with(rom_with(&[]));
+    assert!(!bus.warn_unmapped(0xff03));
+    bus.set_strict(true);
+    for &addr in [0xff03, 0xfea0, 0xff4c, 0xa000].iter() {
+        assert!(bus.warn_unmapped(addr), "{:04x}", addr);
+    }
+    for &addr in [0x0000, 0xc000, 0xfe00, 0xff00, 0xff80, 0xffff].iter() {
+        assert!(!bus.warn_unmapped(addr), "{:04x}", addr);
+    }
+    // Cartridge RAM is only mapped while it's enabled.
+    let mut rom = rom_with(&[]);
+    rom[0x147] = 0x3;
+    rom[0x149] = 0x2;
+    let mut bus = bus_with(rom);
+    bus.set_strict(true);
+    assert!(bus.warn_unmapped(0xa000));
+    bus.w8(0x0000, 0x0a);
+    assert!(!bus.warn_unmapped(0xa000));
+}
+
+#[test]
+fn dma_progress() {
+    let mut bus = bus_with(rom_with(&[]));
+    // Sources past 0xdf wrap onto work RAM.
+    bus.w8(0xff46, 0xe1);
+    assert!(bus.dma().is_starting() && !bus.dma().is_active());
+    assert_eq!(bus.dma().get_address(), 0xe1);
+    while !bus.dma().is_active() {
+        bus.update(4);
+    }
+    assert!(!bus.dma().is_starting());
+    assert_eq!(bus.dma().get_source(), 0xc100);
+    let progress = bus.dma().get_progress().unwrap();
+    bus.update(4);
+    assert_eq!(bus.dma().get_progress(), Some(progress + 1));
+    while bus.dma().is_active() {
+        bus.update(4);
+    }
+    assert_eq!(bus.dma().get_progress(), None);
+}
+
+#[test]
+fn interrupt_registers() {
+    let mut bus = bus_with(rom_with(&[]));
+    bus.w8(0xffff, 0x05);
+    bus.w8(0xff0f, 0x18);
+    assert_eq!(bus.interrupts_enabled(), Interrupt::VBLANK | Interrupt::TIMER);
+    assert_eq!(bus.interrupts_requested(), Interrupt::SERIAL | Interrupt::JOYPAD);
+    // The unused bits of IF read as ones.
+    assert_eq!(bus.r8(0xff0f), 0xf8);
+    assert_eq!(bus.get_highest_priority_interrupt(), None);
+    bus.w8(0xffff, 0x1f);
+    assert_eq!(bus.get_highest_priority_interrupt(), Some(Interrupt::SERIAL));
+}
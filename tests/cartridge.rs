@@ -0,0 +1,129 @@
+extern crate gamebust;
+
+use gamebust::cartridge::{Cartridge,MemoryController};
+
+// A cartridge of the given type with `banks` ROM banks, each starting
+// with its own bank number.
+fn cartridge(kind: u8, ram_size: u8, banks: usize) -> Cartridge {
+    let mut rom = vec![0; banks * 0x4000];
+    for bank in 0..banks {
+        rom[bank * 0x4000] = bank as u8;
+    }
+    rom[0x147] = kind;
+    rom[0x149] = ram_size;
+    Cartridge::new(rom)
+}
+
+#[test]
+fn mbc1_rom_banks() {
+    let mut cart = cartridge(0x1, 0, 64);
+    assert_eq!(cart.r8(0x4000), 1);
+    // Bank 0 can't be selected at 0x4000.
+    cart.w8(0x2000, 0);
+    assert_eq!(cart.r8(0x4000), 1);
+    cart.w8(0x2000, 5);
+    assert_eq!(cart.r8(0x4000), 5);
+    cart.w8(0x4000, 1);
+    assert_eq!((cart.r8(0x4000), cart.bank_at(0x4000)), (0x25, 0x25));
+    assert_eq!(cart.bank_at(0x0000), 0);
+    // In RAM banking mode the upper bits also switch bank 0.
+    cart.w8(0x6000, 1);
+    assert_eq!((cart.r8(0x0000), cart.bank_at(0x0000)), (0x20, 0x20));
+
+    // Banks past the end of the ROM wrap around.
+    let mut small = cartridge(0x1, 0, 4);
+    small.w8(0x2000, 6);
+    assert_eq!(small.r8(0x4000), 2);
+}
+
+#[test]
+fn mbc1_ram() {
+    let mut cart = cartridge(0x3, 0x3, 4);
+    assert!(cart.has_battery());
+    assert_eq!((cart.ram().len(), cart.ram_banks()), (0x8000, 4));
+    // RAM is disabled until 0x0a is written to 0x0000-0x1fff.
+    cart.write_ram(0x10, 0x42);
+    assert_eq!(cart.read_ram(0x10), 0xff);
+    cart.w8(0x0000, 0x0a);
+    cart.write_ram(0x10, 0x42);
+    assert_eq!(cart.read_ram(0x10), 0x42);
+
+    cart.w8(0x6000, 1);
+    cart.w8(0x4000, 2);
+    assert_eq!(cart.ram_bank(), 2);
+    assert_eq!(cart.read_ram(0x10), 0);
+    cart.write_ram(0x10, 0x43);
+    assert_eq!((cart.read_ram_bank(0, 0x10), cart.read_ram_bank(2, 0x10)), (0x42, 0x43));
+
+    cart.w8(0x0000, 0);
+    assert_eq!(cart.read_ram(0x10), 0xff);
+}
+
+#[test]
+fn mbc2() {
+    let mut cart = cartridge(0x5, 0, 16);
+    assert_eq!(cart.ram().len(), 0x200);
+    // Address bit 8 picks between RAM enable and the ROM bank.
+    cart.w8(0x0100, 3);
+    assert_eq!(cart.r8(0x4000), 3);
+    assert_eq!(cart.read_ram(0), 0xff);
+    cart.w8(0x2000, 0x0a);
+    assert_eq!(cart.r8(0x4000), 3);
+    assert_eq!(cart.read_ram(0), 0xf0);
+    // RAM holds half-bytes, repeated across the range.
+    cart.write_ram(0x001, 0x5c);
+    assert_eq!(cart.read_ram(0x001), 0xfc);
+    assert_eq!(cart.read_ram(0x201), 0xfc);
+}
+
+#[test]
+fn ram_without_a_controller() {
+    let mut cart = cartridge(0x8, 0x2, 2);
+    cart.write_ram(0x1fff, 0x42);
+    assert_eq!(cart.read_ram(0x1fff), 0x42);
+    assert!(!cartridge(0x0, 0x2, 2).ram_accessible());
+}
+
+#[test]
+fn debugger_access() {
+    let mut cart = cartridge(0x1, 0, 4);
+    cart.w8(0x2000, 2);
+    cart.patch_rom(0x4001, 0x99);
+    assert_eq!(cart.r8(0x4001), 0x99);
+    assert_eq!(cart.read_rom_bank(2, 0x4001), 0x99);
+    assert_eq!(cart.read_rom_bank(3, 0x4000), 3);
+    assert_eq!(cart.read_rom_bank(3, 0x0000), 3);
+    cart.w8(0x2000, 3);
+    assert_eq!(cart.r8(0x4001), 0);
+}
+
+#[test]
+fn accessors() {
+    // MBC1+RAM+BATTERY with 8KiB of RAM.
+    let mut rom = vec![0; 8 * 0x4000];
+    rom[0x134..0x13b].copy_from_slice(b"TETRIS\0");
+    rom[0x147] = 0x3;
+    rom[0x149] = 0x2;
+    let mut cart = Cartridge::new(rom);
+    assert_eq!(cart.title(), "TETRIS");
+    assert!(cart.has_battery());
+    assert_eq!((cart.rom_banks(), cart.ram_banks()), (8, 1));
+    cart.w8(0x2000, 3);
+    match cart.controller() {
+        MemoryController::MBC1 { rom_bank, .. } => assert_eq!(rom_bank, 3),
+        other => panic!("Expected MBC1, got {:?}", other),
+    }
+    assert!(!cart.ram_enabled());
+    cart.w8(0x0000, 0x0a);
+    assert!(cart.ram_enabled());
+
+    // ROM+RAM, where 2KiB of RAM still counts as a bank.
+    let cart = cartridge(0x8, 0x1, 2);
+    assert_eq!(cart.title(), "");
+    assert!(!cart.has_battery());
+    assert_eq!((cart.rom_banks(), cart.ram_banks()), (2, 1));
+    match cart.controller() {
+        MemoryController::None => {},
+        other => panic!("Expected no controller, got {:?}", other),
+    }
+}
@@ -0,0 +1,41 @@
+// Fixtures shared by the integration tests. Each test file only uses
+// some of them.
+#![allow(dead_code)]
+
+use gamebust::bus::{Bus,FlatBus};
+use gamebust::cpu::Cpu;
+
+pub const START: u16 = 0x100;
+
+// A CPU on a flat bus, about to run `program` from START.
+pub fn cpu_with(program: &[u8]) -> Cpu<FlatBus> {
+    let mut bus = FlatBus::new();
+    bus.load(START, program);
+    let mut cpu = Cpu::with_bus(bus);
+    cpu.reg.pc = START;
+    cpu.reg.sp = 0xfffe;
+    cpu
+}
+
+// A 32KiB cartridge without a memory controller, with `program` at
+// the entry point.
+pub fn rom_with(program: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[START as usize..START as usize + program.len()].copy_from_slice(program);
+    rom
+}
+
+// A bus with the boot ROM already finished.
+pub fn bus_with(rom: Vec<u8>) -> Bus {
+    let mut bus = Bus::with_rom(rom);
+    bus.w8(0xff50, 1);
+    bus
+}
+
+// A whole Game Boy, about to run `program` from START.
+pub fn game_boy(program: &[u8]) -> Cpu {
+    let mut cpu = Cpu::with_bus(bus_with(rom_with(program)));
+    cpu.reg.pc = START;
+    cpu.reg.sp = 0xfffe;
+    cpu
+}
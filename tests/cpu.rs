@@ -0,0 +1,279 @@
+extern crate gamebust;
+
+mod common;
+
+use gamebust::bus::{BusCycle,FlatBus,MemoryBus};
+use gamebust::cpu::{Cpu,Interrupt};
+use gamebust::debugger::DebugState;
+use gamebust::registers::{Reg8,Reg16};
+
+use common::{START,bus_with,cpu_with,rom_with};
+
+// Runs one instruction and returns the number of machine cycles taken.
+fn step(cpu: &mut Cpu<FlatBus>) -> u32 {
+    cpu.bus.clear_cycles();
+    let cycles = cpu.step(&mut DebugState::new());
+    assert_eq!(cycles / 4, cpu.bus.cycles().len() as u32);
+    cycles / 4
+}
+
+#[test]
+fn machine_cycles() {
+    let cases: &[(&str, &[u8], u32)] = &[
+        ("NOP",          &[0x00],             1),
+        ("LD BC,d16",    &[0x01, 0x34, 0x12], 3),
+        ("LD (a16),SP",  &[0x08, 0x00, 0xc0], 5),
+        ("ADD HL,BC",    &[0x09],             2),
+        ("JR NZ (taken)",&[0x20, 0x05],       3),
+        ("JR Z (not)",   &[0x28, 0x05],       2),
+        ("INC (HL)",     &[0x34],             3),
+        ("LD (HL),d8",   &[0x36, 0x42],       3),
+        ("LD A,(HL)",    &[0x7e],             2),
+        ("ADD A,d8",     &[0xc6, 0x01],       2),
+        ("RET",          &[0xc9],             4),
+        ("RET NZ",       &[0xc0],             5),
+        ("RET Z",        &[0xc8],             2),
+        ("POP BC",       &[0xc1],             3),
+        ("PUSH BC",      &[0xc5],             4),
+        ("JP a16",       &[0xc3, 0x00, 0x02], 4),
+        ("CALL a16",     &[0xcd, 0x00, 0x02], 6),
+        ("RST 38",       &[0xff],             4),
+        ("LDH (a8),A",   &[0xe0, 0x80],       3),
+        ("LD (C),A",     &[0xe2],             2),
+        ("ADD SP,e",     &[0xe8, 0x01],       4),
+        ("JP HL",        &[0xe9],             1),
+        ("LD HL,SP+e",   &[0xf8, 0x01],       3),
+        ("LD SP,HL",     &[0xf9],             2),
+        ("BIT 0,B",      &[0xcb, 0x40],       2),
+        ("BIT 0,(HL)",   &[0xcb, 0x46],       3),
+        ("SET 0,(HL)",   &[0xcb, 0xc6],       4),
+    ];
+    for &(name, program, expected) in cases {
+        let mut cpu = cpu_with(program);
+        cpu.reg.w16(Reg16::HL, 0xc000);
+        assert_eq!(step(&mut cpu), expected, "{}", name);
+    }
+}
+
+#[test]
+fn push_writes_high_byte_first() {
+    let mut cpu = cpu_with(&[0xc5]);
+    cpu.reg.w16(Reg16::BC, 0x1234);
+    step(&mut cpu);
+    assert_eq!(cpu.bus.cycles(), &[BusCycle::Read(START, 0xc5),
+                                   BusCycle::Internal,
+                                   BusCycle::Write(0xfffd, 0x12),
+                                   BusCycle::Write(0xfffc, 0x34)]);
+    assert_eq!(cpu.reg.sp, 0xfffc);
+}
+
+#[test]
+fn pop_af_clears_low_flag_bits() {
+    let mut cpu = cpu_with(&[0xf1]);
+    cpu.bus.load(0xfffe, &[0xff, 0xab]);
+    cpu.reg.sp = 0xfffe;
+    step(&mut cpu);
+    assert_eq!(cpu.reg.r16(Reg16::AF), 0xabf0);
+}
+
+#[test]
+fn daa_after_addition() {
+    // LD A,0x45; ADD A,0x38; DAA
+    let mut cpu = cpu_with(&[0x3e, 0x45, 0xc6, 0x38, 0x27]);
+    step(&mut cpu);
+    step(&mut cpu);
+    step(&mut cpu);
+    assert_eq!(cpu.reg.r8(Reg8::A), 0x83);
+    assert!(!cpu.reg.f_c);
+}
+
+#[test]
+fn add_sp_sets_carries_from_low_byte() {
+    let mut cpu = cpu_with(&[0xe8, 0x01]);
+    cpu.reg.sp = 0x00ff;
+    step(&mut cpu);
+    assert_eq!(cpu.reg.sp, 0x0100);
+    assert!(!cpu.reg.f_z && !cpu.reg.f_n && cpu.reg.f_h && cpu.reg.f_c);
+}
+
+#[test]
+fn call_and_return() {
+    let mut cpu = cpu_with(&[0xcd, 0x00, 0x02]);
+    cpu.bus.load(0x200, &[0xc9]);
+    step(&mut cpu);
+    assert_eq!(cpu.reg.pc, 0x200);
+    assert_eq!(cpu.bus.peek(0xfffd), 0x01);
+    assert_eq!(cpu.bus.peek(0xfffc), 0x03);
+    step(&mut cpu);
+    assert_eq!(cpu.reg.pc, START + 3);
+    assert_eq!(cpu.reg.sp, 0xfffe);
+}
+
+#[test]
+fn stop_waits_for_a_key() {
+    // STOP; NOP. Nothing is pressed on a flat bus.
+    let mut cpu = cpu_with(&[0x10, 0x00, 0x00]);
+    step(&mut cpu);
+    assert!(cpu.is_stopped());
+    for _ in 0..10 {
+        assert_eq!(cpu.step(&mut DebugState::new()), 4);
+    }
+    assert!(cpu.is_stopped() && !cpu.is_halted());
+    assert_eq!(cpu.reg.pc, START + 2);
+}
+
+// A CPU with the timer interrupt enabled and a handler of INC B; RETI.
+fn with_timer_interrupt(program: &[u8]) -> Cpu<FlatBus> {
+    let mut cpu = cpu_with(program);
+    cpu.bus.map_interrupts();
+    cpu.bus.load(0xffff, &[Interrupt::TIMER.bits()]);
+    cpu.bus.load(0x50, &[0x04, 0xd9]);
+    cpu
+}
+
+fn request_timer(cpu: &mut Cpu<FlatBus>) {
+    cpu.bus.load(0xff0f, &[Interrupt::TIMER.bits()]);
+}
+
+#[test]
+fn halt_bug_repeats_the_next_byte() {
+    // HALT; INC B; NOP with IME off and an interrupt pending.
+    let mut cpu = with_timer_interrupt(&[0x76, 0x04, 0x00]);
+    request_timer(&mut cpu);
+    step(&mut cpu);
+    assert!(!cpu.is_halted());
+    step(&mut cpu);
+    assert_eq!(cpu.reg.pc, START + 1);
+    step(&mut cpu);
+    assert_eq!(cpu.reg.pc, START + 2);
+    assert_eq!(cpu.reg.r8(Reg8::B), 2);
+}
+
+#[test]
+fn ei_takes_effect_after_the_next_instruction() {
+    let mut cpu = with_timer_interrupt(&[0xfb, 0x00, 0x00]);
+    request_timer(&mut cpu);
+    step(&mut cpu);
+    assert_eq!(cpu.reg.pc, START + 1);
+    assert!(cpu.interrupts_pending_enable());
+    step(&mut cpu);
+    assert_eq!(cpu.reg.pc, START + 2);
+    assert!(!cpu.interrupts_pending_enable());
+
+    assert_eq!(step(&mut cpu), 5);
+    assert_eq!(cpu.reg.pc, 0x50);
+    assert_eq!(cpu.reg.sp, 0xfffc);
+    assert_eq!(cpu.bus.peek(0xfffd), 0x01);
+    assert_eq!(cpu.bus.peek(0xfffc), 0x02);
+    assert_eq!(cpu.bus.peek(0xff0f), 0);
+    assert!(!cpu.interrupts_enabled());
+}
+
+#[test]
+fn di_straight_after_ei_cancels_it() {
+    let mut cpu = with_timer_interrupt(&[0xfb, 0xf3, 0x00, 0x00]);
+    request_timer(&mut cpu);
+    for _ in 0..4 {
+        step(&mut cpu);
+    }
+    assert_eq!(cpu.reg.pc, START + 4);
+    assert!(!cpu.interrupts_enabled());
+}
+
+#[test]
+fn ei_then_halt_returns_to_the_halt() {
+    // EI; HALT; INC B
+    let mut cpu = with_timer_interrupt(&[0xfb, 0x76, 0x04]);
+    request_timer(&mut cpu);
+    step(&mut cpu);
+    step(&mut cpu);
+    step(&mut cpu);
+    assert_eq!(cpu.reg.pc, 0x50);
+    assert_eq!(cpu.bus.peek(0xfffd), 0x01);
+    assert_eq!(cpu.bus.peek(0xfffc), 0x01);
+
+    step(&mut cpu);
+    step(&mut cpu);
+    assert_eq!(cpu.reg.pc, START + 1);
+    step(&mut cpu);
+    assert!(cpu.is_halted());
+    assert_eq!(cpu.reg.r8(Reg8::B), 1);
+}
+
+#[test]
+fn reti_enables_interrupts_at_once() {
+    let mut cpu = with_timer_interrupt(&[0x00, 0x00]);
+    cpu.set_interrupts_enabled(true);
+    request_timer(&mut cpu);
+    step(&mut cpu);
+    assert_eq!(cpu.reg.pc, 0x50);
+    request_timer(&mut cpu);
+    step(&mut cpu);
+    step(&mut cpu);
+    assert!(cpu.interrupts_enabled());
+    assert_eq!(cpu.reg.pc, START);
+    step(&mut cpu);
+    assert_eq!(cpu.reg.pc, 0x50);
+}
+
+#[test]
+fn halt_wakes_on_an_interrupt() {
+    let mut cpu = with_timer_interrupt(&[0x76, 0x00]);
+    cpu.set_interrupts_enabled(true);
+    step(&mut cpu);
+    step(&mut cpu);
+    assert!(cpu.is_halted());
+    assert_eq!(cpu.reg.pc, START + 1);
+    request_timer(&mut cpu);
+    step(&mut cpu);
+    assert!(!cpu.is_halted());
+    assert_eq!(cpu.reg.pc, 0x50);
+    assert_eq!(cpu.bus.peek(0xfffc), 0x01);
+}
+
+#[test]
+fn pushing_over_ie_cancels_the_interrupt() {
+    // The high byte of PC (0x01) lands in IE and disables the timer.
+    let mut cpu = with_timer_interrupt(&[0x00]);
+    cpu.set_interrupts_enabled(true);
+    cpu.reg.sp = 0x0000;
+    request_timer(&mut cpu);
+    step(&mut cpu);
+    assert_eq!(cpu.reg.pc, 0x0000);
+    assert_eq!(cpu.reg.sp, 0xfffe);
+    assert_eq!(cpu.bus.peek(0xffff), 0x01);
+    assert_eq!(cpu.bus.peek(0xff0f), Interrupt::TIMER.bits());
+}
+
+#[test]
+fn pushing_over_ie_can_change_the_interrupt() {
+    // IE becomes 0x01, so VBLANK is the only interrupt both enabled and
+    // requested, and it is taken instead.
+    let mut cpu = with_timer_interrupt(&[0x00]);
+    cpu.set_interrupts_enabled(true);
+    cpu.reg.sp = 0x0000;
+    cpu.bus.load(0xff0f, &[(Interrupt::TIMER | Interrupt::VBLANK).bits()]);
+    cpu.bus.load(0xffff, &[(Interrupt::TIMER | Interrupt::LCD_STAT).bits()]);
+    step(&mut cpu);
+    assert_eq!(cpu.reg.pc, 0x40);
+    assert_eq!(cpu.bus.peek(0xff0f), Interrupt::TIMER.bits());
+}
+
+// During OAM DMA the CPU fetches whatever byte is being copied, so it
+// runs LD B,0x06 from the transfer instead of the NOPs in ROM.
+#[test]
+fn fetches_see_dma_conflicts() {
+    let mut bus = bus_with(rom_with(&[0x00, 0x00]));
+    for i in 0..0xa0 {
+        bus.w8(0xc000 + i, 0x06);
+    }
+    bus.w8(0xff46, 0xc0);
+    while !bus.dma().is_active() {
+        bus.update(4);
+    }
+    let mut cpu = Cpu::with_bus(bus);
+    cpu.reg.pc = START;
+    cpu.step(&mut DebugState::new());
+    assert_eq!(cpu.reg.r8(Reg8::B), 0x06);
+    assert_eq!(cpu.reg.pc, START + 2);
+}
@@ -0,0 +1,254 @@
+extern crate gamebust;
+
+mod common;
+
+use gamebust::bus::FlatBus;
+use gamebust::cpu::Cpu;
+use gamebust::debugger::{DebugState,Debugger,RunTo,WatchKind};
+use gamebust::expr::Expr;
+use gamebust::registers::{Reg8,Reg16};
+use gamebust::symbols::Symbols;
+
+use common::{START,cpu_with};
+
+// Steps until the debugger would pause, returning the number of
+// instructions run, or None if it doesn't within `limit`.
+fn run_until_paused(cpu: &mut Cpu<FlatBus>, debug: &mut DebugState, limit: u32) -> Option<u32> {
+    for i in 1..limit + 1 {
+        cpu.step(debug);
+        if debug.paused {
+            debug.paused = false;
+            return Some(i);
+        }
+    }
+    None
+}
+
+fn eval(cpu: &Cpu<FlatBus>, text: &str) -> Result<i64, String> {
+    Expr::parse(text).and_then(|expr| expr.eval(&cpu.reg, &cpu.bus))
+}
+
+#[test]
+fn expressions() {
+    let mut cpu = cpu_with(&[]);
+    cpu.reg.w8(Reg8::A, 0x3f);
+    cpu.reg.w16(Reg16::BC, 0xc000);
+    cpu.reg.f_c = true;
+    cpu.bus.load(0xff44, &[91]);
+    cpu.bus.load(0xc000, &[0x12]);
+    assert_eq!(eval(&cpu, "A == 0x3f && [ff44] > 90"), Ok(1));
+    assert_eq!(eval(&cpu, "a == $3f && [0xff44] > 91"), Ok(0));
+    assert_eq!(eval(&cpu, "[BC] + 1"), Ok(0x13));
+    assert_eq!(eval(&cpu, "[bc + 1] | cf << 4"), Ok(0x10));
+    assert_eq!(eval(&cpu, "1 + 2 * 3 == 7 || 1 / 0"), Ok(1));
+    assert_eq!(eval(&cpu, "-(PC - 0x101)"), Ok(1));
+    assert_eq!(eval(&cpu, "!ZF && ~0 == -1"), Ok(1));
+    assert!(eval(&cpu, "1 / (A - A)").is_err());
+    assert!(eval(&cpu, "A ==").is_err());
+    assert!(eval(&cpu, "(A").is_err());
+    assert!(eval(&cpu, "Q").is_err());
+}
+
+#[test]
+fn conditional_breakpoints() {
+    // INC A; JR -3
+    let mut cpu = cpu_with(&[0x3c, 0x18, 0xfd]);
+    cpu.reg.w8(Reg8::A, 0);
+    let mut debug = DebugState::new();
+    let condition = Expr::parse("A == 3").unwrap();
+    debug.add_breakpoint(None, START, Some(("A == 3".to_string(), condition)));
+    // The breakpoint is checked after each instruction, when PC is
+    // back at the start of the loop with A already incremented.
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(6));
+    assert_eq!(cpu.reg.r8(Reg8::A), 3);
+    assert_eq!(debug.breakpoints[0].hits, 1);
+    assert!(debug.stop_reason.take().unwrap().starts_with("Breakpoint 1 at 0100"));
+}
+
+#[test]
+fn ignore_counts() {
+    let mut cpu = cpu_with(&[0x3c, 0x18, 0xfd]);
+    cpu.reg.w8(Reg8::A, 0);
+    let mut debug = DebugState::new();
+    debug.add_breakpoint(None, START, None);
+    debug.breakpoints[0].ignore = 2;
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(6));
+    assert_eq!(debug.breakpoints[0].hits, 3);
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(2));
+    assert_eq!(debug.breakpoints[0].hits, 4);
+}
+
+#[test]
+fn banked_breakpoints() {
+    // JP 0x4000, with INC A; JR -3 in switchable ROM, which is always
+    // bank 1 on a flat bus.
+    let mut cpu = cpu_with(&[0xc3, 0x00, 0x40]);
+    cpu.bus.load(0x4000, &[0x3c, 0x18, 0xfd]);
+    cpu.reg.w8(Reg8::A, 0);
+    let mut debug = DebugState::new();
+    debug.add_breakpoint(Some(2), 0x4000, None);
+    debug.add_breakpoint(Some(1), 0x4000, None);
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(1));
+    assert_eq!(debug.stop_reason.take().unwrap(), "Breakpoint 2 at 01:4000, hit 1 time");
+    assert_eq!(debug.breakpoints[0].hits, 0);
+
+    debug.breakpoints.remove(1);
+    debug.add_watchpoint(Some(2), 0x4000, 0x7fff, WatchKind::Access, None);
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), None);
+}
+
+#[test]
+fn watchpoints() {
+    // LD HL,0xc100; LD A,(HL); LD (HL),0; JR -5
+    let mut cpu = cpu_with(&[0x21, 0x00, 0xc1, 0x7e, 0x36, 0x00, 0x18, 0xfb]);
+    cpu.bus.load(0xc100, &[0x05]);
+    let mut debug = DebugState::new();
+    debug.add_watchpoint(None, 0xc0f0, 0xc1ff, WatchKind::Write, Some(0));
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(3));
+    assert_eq!(debug.stop_reason.take().unwrap(),
+               "Watchpoint 1: write to c100: 05 -> 00 by instruction at 0104");
+
+    // Reads don't count instruction fetches.
+    debug.watchpoints.clear();
+    debug.add_watchpoint(None, 0x0100, 0x0107, WatchKind::Access, None);
+    debug.add_watchpoint(None, 0xc100, 0xc100, WatchKind::Read, None);
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(2));
+    assert_eq!(debug.stop_reason.take().unwrap(),
+               "Watchpoint 3: read 00 from c100 by instruction at 0103");
+    assert_eq!(debug.watchpoints[0].hits, 0);
+}
+
+// CALL 0x0200; NOP, with a function at 0x0200 that calls itself once
+// before returning: INC A; CP 2; CALL NZ,0x0200; RET
+fn recursive_program() -> Cpu<FlatBus> {
+    let mut cpu = cpu_with(&[0xcd, 0x00, 0x02, 0x00]);
+    cpu.bus.load(0x200, &[0x3c, 0xfe, 0x02, 0xc4, 0x00, 0x02, 0xc9]);
+    cpu.reg.w8(Reg8::A, 0);
+    cpu
+}
+
+#[test]
+fn next_steps_over_recursive_calls() {
+    let mut cpu = recursive_program();
+    let mut debug = DebugState::new();
+    debug.set_run_to(RunTo::Next(START + 3, cpu.reg.sp));
+    // The inner call returns to 0x0206, not START + 3, and the outer
+    // one only returns once the inner one has.
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(9));
+    assert_eq!(cpu.reg.pc, START + 3);
+    assert_eq!(debug.run_to, None);
+}
+
+#[test]
+fn finish_returns_from_the_current_function() {
+    let mut cpu = recursive_program();
+    let mut debug = DebugState::new();
+    // Run into the outer call, then on into the inner one.
+    debug.set_run_to(RunTo::Until(0x200));
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(1));
+    debug.set_run_to(RunTo::Until(0x200));
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(3));
+    debug.set_run_to(RunTo::Finish(cpu.reg.sp));
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(4));
+    assert_eq!(cpu.reg.pc, 0x206);
+    debug.set_run_to(RunTo::Finish(cpu.reg.sp));
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(1));
+    assert_eq!(cpu.reg.pc, START + 3);
+}
+
+// CALL 0x0200; NOP, with a function at 0x0200 that returns by popping
+// its return address itself: POP HL; INC A; JP (HL)
+#[test]
+fn next_and_finish_follow_the_stack() {
+    let mut cpu = cpu_with(&[0xcd, 0x00, 0x02, 0x00]);
+    cpu.bus.load(0x200, &[0xe1, 0x3c, 0xe9]);
+    let mut debug = DebugState::new();
+    debug.set_run_to(RunTo::Next(START + 3, cpu.reg.sp));
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(4));
+    assert_eq!(cpu.reg.pc, START + 3);
+
+    let mut cpu = cpu_with(&[0xcd, 0x00, 0x02, 0x00]);
+    cpu.bus.load(0x200, &[0xe1, 0x3c, 0xe9]);
+    cpu.step(&mut debug);
+    debug.set_run_to(RunTo::Finish(cpu.reg.sp));
+    // The return address is gone as soon as it is popped.
+    assert_eq!(run_until_paused(&mut cpu, &mut debug, 100), Some(1));
+    assert_eq!(cpu.reg.pc, 0x201);
+}
+
+#[test]
+fn call_stack() {
+    let mut cpu = recursive_program();
+    let mut debug = DebugState::new();
+    debug.set_run_to(RunTo::Until(0x206));
+    run_until_paused(&mut cpu, &mut debug, 100);
+    let frames: Vec<(u16, u16, u16)> = cpu.call_stack().iter()
+        .map(|frame| (frame.call_site, frame.target, frame.return_addr)).collect();
+    // Stopped at the inner call's RET.
+    assert_eq!(frames, vec![(START, 0x200, START + 3), (0x203, 0x200, 0x206)]);
+    cpu.step(&mut debug);
+    assert_eq!(cpu.call_stack().len(), 1);
+    cpu.step(&mut debug);
+    assert!(cpu.call_stack().is_empty());
+}
+
+#[test]
+fn call_stack_follows_stack_pointer() {
+    // CALL 0x0200, where the function pops its own return address
+    // (POP HL; JP (HL)) instead of returning.
+    let mut cpu = cpu_with(&[0xcd, 0x00, 0x02]);
+    cpu.bus.load(0x200, &[0xe1, 0xe9]);
+    let mut debug = DebugState::new();
+    cpu.step(&mut debug);
+    assert_eq!(cpu.call_stack().len(), 1);
+    assert!(!cpu.call_stack()[0].interrupt);
+    cpu.step(&mut debug);
+    assert!(cpu.call_stack().is_empty());
+}
+
+#[test]
+fn symbols() {
+    let symbols = Symbols::parse("; File generated by rgblink\n\
+                                  00:0150 Main\n\
+                                  00:0158 Main.loop\n\
+                                  01:4000 Banked\n\
+                                  02:4000 OtherBank\n\
+                                  00:c000 wCounter\n");
+    assert_eq!(symbols.len(), 5);
+    assert_eq!(symbols.lookup("Main.loop"), Some((0, 0x158)));
+    assert_eq!(symbols.lookup("Missing"), None);
+
+    assert_eq!(symbols.label(0, 0x150), Some("Main"));
+    assert_eq!(symbols.describe(0, 0x15b), Some("Main.loop+0x3".to_string()));
+    assert_eq!(symbols.describe(2, 0x4010), Some("OtherBank+0x10".to_string()));
+    assert_eq!(symbols.describe(1, 0x4001), Some("Banked+0x1".to_string()));
+    // Labels don't reach past the end of their area of memory, and
+    // banks only matter for switchable ROM.
+    assert_eq!(symbols.describe(3, 0x4000), None);
+    assert_eq!(symbols.describe(0, 0xd000), Some("wCounter+0x1000".to_string()));
+    assert_eq!(symbols.describe(5, 0xc001), Some("wCounter+0x1".to_string()));
+    assert_eq!(symbols.describe(0, 0x100), None);
+
+    let mut cpu = cpu_with(&[]);
+    cpu.bus.load(0xc000, &[7]);
+    let expr = Expr::parse_with_symbols("[wCounter] == 7 && PC == Main - 0x50", Some(&symbols)).unwrap();
+    assert_eq!(expr.eval(&cpu.reg, &cpu.bus), Ok(1));
+    assert!(Expr::parse("[wCounter]").is_err());
+}
+
+#[test]
+fn abbreviated_commands() {
+    let debugger = Debugger::new();
+    let expected = [
+        ("c", "continue"), ("r", "registers"), ("s", "step"), ("n", "next"), ("b", "breakpoint"),
+        ("w", "watchpoint"), ("d", "delete"), ("x", "xamine"), ("l", "list"), ("sp", "sprites"),
+        ("fin", "finish"), ("fr", "frame"), ("ba", "backtrace"), ("int", "int"), ("lc", "lcd"),
+    ];
+    for &(abbreviation, name) in expected.iter() {
+        assert_eq!(debugger.command_name(abbreviation), Ok(name), "{}", abbreviation);
+    }
+    // Anything else has to pick out one command.
+    assert_eq!(debugger.command_name("f"), Err("Did you mean: fill flag finish frame".to_string()));
+    assert_eq!(debugger.command_name("fi"), Err("Did you mean: fill finish".to_string()));
+    assert_eq!(debugger.command_name("zzz"), Err("Unknown command: zzz".to_string()));
+}
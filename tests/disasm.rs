@@ -0,0 +1,167 @@
+extern crate gamebust;
+
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use gamebust::assembler::assemble;
+use gamebust::disasm::{self,Syntax};
+use gamebust::instructions::{Cond,Instr,decode};
+
+use common::rom_with;
+
+#[test]
+fn decode_slices() {
+    assert_eq!(decode(&[0xc3, 0x50, 0x01]), Some((Instr::Jump(0x0150, Cond::Always), 3)));
+    assert_eq!(decode(&[0xcb, 0x7c, 0xff]), Some((decode(&[0xcb, 0x7c]).unwrap().0, 2)));
+    assert_eq!(decode(&[0xc3, 0x50]), None);
+    assert_eq!(decode(&[]), None);
+}
+
+#[test]
+fn display() {
+    let text = |bytes: &[u8]| decode(bytes).unwrap().0.to_string();
+    assert_eq!(text(&[0xf2]), "LD A,(0xff00+C)");
+    assert_eq!(text(&[0xe0, 0x40]), "LD (0xff00+0x40),A");
+    assert_eq!(text(&[0xfa, 0x05, 0x00]), "LD A,(0x0005)");
+    assert_eq!(text(&[0xcc, 0x34, 0x12]), "CALL Z,0x1234");
+    assert_eq!(text(&[0xf8, 0xfe]), "LD HL,SP-2");
+    assert_eq!(text(&[0xe8, 0x02]), "ADD SP,2");
+}
+
+#[test]
+fn labels_branch_targets() {
+    // JR -2 loops on itself.
+    let rom = [0x00, 0x18, 0xfe];
+    let mut out = Vec::new();
+    disasm::disassemble(&mut out, &rom, 1, 0, 2, Syntax::Rgbds).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               "SECTION \"disasm_0000\", ROM0[$0000]\n\tnop\nlabel_0001:\n\tjr label_0001\n");
+}
+
+#[test]
+fn lists_across_into_the_selected_bank() {
+    let mut rom = vec![0xff; 4 * disasm::BANK_SIZE];
+    // JP 0x4001 at the end of bank 0, then NOP; JR 0x3ffd in bank 2.
+    rom[0x3ffd..0x4000].copy_from_slice(&[0xc3, 0x01, 0x40]);
+    rom[0x8000..0x8003].copy_from_slice(&[0x00, 0x18, 0xfa]);
+    let mut out = Vec::new();
+    disasm::disassemble(&mut out, &rom, 2, 0x3ffd, 0x4002, Syntax::Rgbds).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               "SECTION \"disasm_3ffd\", ROM0[$3ffd]\nlabel_3ffd:\n\tjp label_02_4001\n\
+                SECTION \"disasm_02_4000\", ROMX[$4000], BANK[$02]\n\tnop\nlabel_02_4001:\n\tjr label_3ffd\n");
+
+    // An instruction cut off by the end of bank 0 is data.
+    let mut out = Vec::new();
+    disasm::disassemble(&mut out, &rom, 2, 0x3ffe, 0x4000, Syntax::Native).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               "  00:3ffe  01 40     db $01, $40\n\n  02:4000  00        NOP\n");
+}
+
+#[test]
+fn traces_code_across_banks() {
+    let mut rom = vec![0; 4 * disasm::BANK_SIZE];
+    // LD A,2; LD (0x2000),A; CALL 0x4000; JR -2
+    rom[0x100..0x10a].copy_from_slice(&[0x3e, 0x02, 0xea, 0x00, 0x20, 0xcd, 0x00, 0x40, 0x18, 0xfe]);
+    // NOP; RET in bank 2, then bytes that are never reached.
+    rom[0x8000..0x8003].copy_from_slice(&[0x00, 0xc9, 0x3e]);
+    let map = disasm::trace_code(&rom);
+    assert!(map.is_code(0x100) && map.is_code(0x108));
+    assert!(!map.is_code(0x10a));
+    assert!(map.is_code(0x8000) && map.is_code(0x8001));
+    assert!(!map.is_code(0x8002) && !map.is_code(0x4000) && !map.is_code(0xc000));
+    assert_eq!(map.label(0x8000), Some("label_02_4000".to_string()));
+    assert_eq!(map.label(0x108), Some("label_0108".to_string()));
+}
+
+// Turns a line of RGBDS into the assembler's own syntax, with labels
+// replaced by their addresses. Unknown labels, on the first pass, are
+// taken to be `addr` itself.
+fn native_syntax(line: &str, addr: u16, labels: &HashMap<String, u16>) -> String {
+    let mut text = line.replace('[', "(").replace(']', ")");
+    if text.starts_with("ldh") {
+        text = text.replacen("ldh", "ld", 1).replace("($ff", "($ff00+$");
+    }
+    let last = text.rsplit(&[' ', ','][..]).next().unwrap().to_string();
+    if last.starts_with("label_") {
+        let target = labels.get(&last).cloned().unwrap_or(addr);
+        let value = if text.starts_with("jr") {
+            format!("{}", target.wrapping_sub(addr.wrapping_add(2)) as i16)
+        } else {
+            format!("${:04x}", target)
+        };
+        text = format!("{}{}", &text[..text.len() - last.len()], value);
+    }
+    text
+}
+
+// Assembles the banks of a project, checking each file's section. On
+// the first pass `labels` is filled in and the output is thrown away.
+fn assemble_project(dir: &Path, banks: usize, labels: &mut HashMap<String, u16>) -> Vec<u8> {
+    let mut rom = Vec::new();
+    for bank in 0..banks {
+        let text = fs::read_to_string(dir.join(format!("bank_{:02x}.asm", bank))).unwrap();
+        let mut lines = text.lines();
+        let (section, mut addr) = if bank == 0 {
+            ("SECTION \"bank_00\", ROM0[$0000]".to_string(), 0x0000)
+        } else {
+            (format!("SECTION \"bank_{:02x}\", ROMX[$4000], BANK[${:02x}]", bank, bank), 0x4000)
+        };
+        assert_eq!(lines.next(), Some(&section[..]));
+        for line in lines {
+            let bytes = if let Some(label) = line.strip_suffix(':') {
+                labels.insert(label.to_string(), addr);
+                continue;
+            } else if let Some(values) = line.trim().strip_prefix("db ") {
+                values.split(", ").map(|value| u8::from_str_radix(&value[1..], 16).unwrap()).collect()
+            } else {
+                let text = native_syntax(line.trim(), addr, labels);
+                assemble(&text).unwrap_or_else(|e| panic!("{}: {}", text, e))
+            };
+            addr = addr.wrapping_add(bytes.len() as u16);
+            rom.extend(bytes);
+        }
+    }
+    rom
+}
+
+#[test]
+fn project_reassembles_to_the_rom() {
+    let mut rom = rom_with(&[
+        0x00,             // NOP
+        0xcd, 0x00, 0x40, // CALL 0x4000
+        0xf0, 0x44,       // LDH A,(0x44)
+        0xea, 0x00, 0xc0, // LD (0xc000),A
+        0x10, 0x01,       // STOP with odd padding
+        0x22,             // LD (HL+),A
+        0xcb, 0x7c,       // BIT 7,H
+        0x20, 0xfc,       // JR NZ,-4
+        0xc3, 0x0e, 0x01, // JP 0x010e
+        0x12, 0x34,       // Never reached
+    ]);
+    // LD HL,SP-2; LD (0xff00+C),A; RET
+    rom[0x4000..0x4004].copy_from_slice(&[0xf8, 0xfe, 0xe2, 0xc9]);
+
+    let dir = ::std::env::temp_dir().join(format!("gamebust-project-{}", ::std::process::id()));
+    let map = disasm::write_project(&rom, &dir).unwrap();
+    assert!(map.is_code(0x4002) && !map.is_code(0x4004) && !map.is_code(0x113));
+
+    let game = fs::read_to_string(dir.join("game.asm")).unwrap();
+    assert!(game.contains("INCLUDE \"bank_00.asm\"\nINCLUDE \"bank_01.asm\"\n"));
+    let bank_00 = fs::read_to_string(dir.join("bank_00.asm")).unwrap();
+    assert!(bank_00.contains("\tcall label_01_4000\n"));
+    assert!(bank_00.contains("label_010e:\n\tjr nz, label_010c\n"));
+    assert!(bank_00.contains("\tdb $10, $01\n"));
+    assert!(bank_00.contains("\tjp label_010e\n\tdb $12, $34,"));
+
+    // Every byte comes back, in the same place.
+    let mut labels = HashMap::new();
+    assemble_project(&dir, 2, &mut labels);
+    let reassembled = assemble_project(&dir, 2, &mut labels);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(reassembled.len(), rom.len());
+    // Not assert_eq, which would print all 32KiB.
+    assert!(reassembled == rom);
+}
@@ -0,0 +1,29 @@
+extern crate gamebust;
+
+use gamebust::cpu::Interrupt;
+use gamebust::gpu::Gpu;
+
+// Runs the GPU until it starts line `ly`.
+fn run_to_line(gpu: &mut Gpu, ly: u8) {
+    let mut irq = Interrupt::empty();
+    while gpu.get_ly() != ly {
+        gpu.update(4, &mut irq);
+    }
+}
+
+#[test]
+fn line_cycles() {
+    let mut gpu = Gpu::new();
+    // LY reads as zero with the LCD off.
+    gpu.set_control(0x80);
+    let mut irq = Interrupt::empty();
+    // Through each mode of a visible line, then a line of VBlank.
+    for &ly in &[1, 144] {
+        run_to_line(&mut gpu, ly);
+        for i in 0..114 {
+            assert_eq!((gpu.get_ly(), gpu.line_cycles()), (ly, i * 4));
+            gpu.update(4, &mut irq);
+        }
+        assert_eq!((gpu.get_ly(), gpu.line_cycles()), (ly + 1, 0));
+    }
+}
@@ -0,0 +1,75 @@
+extern crate gamebust;
+
+mod common;
+
+use gamebust::cpu::{Cpu,Interrupt};
+use gamebust::debugger::DebugState;
+use gamebust::link::LinkCable;
+use gamebust::registers::Reg8;
+
+use common::game_boy;
+
+// Starts a transfer of `data` with the given SC value, waits for it
+// to finish, then copies the byte received into B and loops forever:
+//     LD A,data; LDH (0x01),A; LD A,control; LDH (0x02),A
+//     wait: LDH A,(0x02); BIT 7,A; JR NZ,wait
+//     LDH A,(0x01); LD B,A; JR -2
+fn exchange(data: u8, control: u8) -> Cpu {
+    let mut cpu = game_boy(&[0x3e, data, 0xe0, 0x01, 0x3e, control, 0xe0, 0x02,
+                             0xf0, 0x02, 0xcb, 0x7f, 0x20, 0xfa,
+                             0xf0, 0x01, 0x47, 0x18, 0xfe]);
+    cpu.reg.w8(Reg8::B, 0);
+    cpu
+}
+
+// Runs both sides for about `cycles` cycles each.
+fn run(left: &mut Cpu, right: &mut Cpu, cycles: u64) {
+    let mut cable = LinkCable::new();
+    cable.connect(left, right);
+    let (mut left_debug, mut right_debug) = (DebugState::new(), DebugState::new());
+    while cable.cycles() < cycles {
+        cable.step(left, right, &mut left_debug, &mut right_debug);
+    }
+}
+
+#[test]
+fn exchanges_bytes() {
+    // The left side drives the clock.
+    let mut left = exchange(0x42, 0x81);
+    let mut right = exchange(0x24, 0x80);
+    run(&mut left, &mut right, 8 * 512 + 1000);
+    assert_eq!(left.reg.r8(Reg8::B), 0x24);
+    assert_eq!(right.reg.r8(Reg8::B), 0x42);
+    assert!(left.bus.interrupts_requested().contains(Interrupt::SERIAL));
+    assert!(right.bus.interrupts_requested().contains(Interrupt::SERIAL));
+}
+
+#[test]
+fn either_side_can_drive_the_clock() {
+    let mut left = exchange(0x42, 0x80);
+    let mut right = exchange(0x24, 0x81);
+    run(&mut left, &mut right, 8 * 512 + 1000);
+    assert_eq!(left.reg.r8(Reg8::B), 0x24);
+    assert_eq!(right.reg.r8(Reg8::B), 0x42);
+}
+
+#[test]
+fn waits_for_a_clock() {
+    // With both sides on the external clock nothing moves.
+    let mut left = exchange(0x42, 0x80);
+    let mut right = exchange(0x24, 0x80);
+    run(&mut left, &mut right, 4 * 8 * 512);
+    assert_eq!(left.bus.r8(0xff02) & 0x80, 0x80);
+    assert_eq!(right.bus.r8(0xff02) & 0x80, 0x80);
+    assert!(!left.bus.interrupts_requested().contains(Interrupt::SERIAL));
+}
+
+#[test]
+fn takes_eight_bits_of_the_internal_clock() {
+    let mut left = exchange(0x42, 0x81);
+    let mut right = exchange(0x24, 0x80);
+    run(&mut left, &mut right, 8 * 512 - 100);
+    assert_eq!(left.reg.r8(Reg8::B), 0);
+    assert_eq!(right.reg.r8(Reg8::B), 0);
+    assert_eq!(right.bus.r8(0xff01), 0x24);
+}
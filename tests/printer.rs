@@ -0,0 +1,120 @@
+extern crate gamebust;
+extern crate png;
+
+use std::fs::{self,File};
+use std::path::{Path,PathBuf};
+
+use gamebust::printer::Printer;
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const STATUS: u8 = 0x0f;
+
+// Sends a packet and returns the printer's answers to its last two
+// bytes: 0x81, then the status.
+fn send_with_checksum(printer: &mut Printer, command: u8, compression: u8, data: &[u8],
+                      checksum: u16) -> (u8, u8) {
+    let mut packet = vec![0x88, 0x33, command, compression, data.len() as u8, (data.len() >> 8) as u8];
+    packet.extend_from_slice(data);
+    packet.extend_from_slice(&[checksum as u8, (checksum >> 8) as u8]);
+    for &byte in packet.iter() {
+        assert_eq!(printer.exchange(byte), 0);
+    }
+    let alive = printer.exchange(0x00);
+    (alive, printer.exchange(0x00))
+}
+
+fn send(printer: &mut Printer, command: u8, compression: u8, data: &[u8]) -> (u8, u8) {
+    let checksum = [command, compression, data.len() as u8, (data.len() >> 8) as u8].iter()
+        .chain(data.iter())
+        .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+    send_with_checksum(printer, command, compression, data, checksum)
+}
+
+// Two rows of tiles, blank except for a black first tile.
+fn band() -> Vec<u8> {
+    let mut data = vec![0; 2 * 20 * 16];
+    for byte in data[..16].iter_mut() {
+        *byte = 0xff;
+    }
+    data
+}
+
+// A printer for tests that never print.
+fn printer() -> Printer {
+    Printer::new(PathBuf::new())
+}
+
+fn output_dir(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("gamebust-{}-{}", name, ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Returns the width, height and pixels of a grayscale PNG.
+fn read_png(path: &Path) -> (u32, u32, Vec<u8>) {
+    let decoder = png::Decoder::new(File::open(path).unwrap());
+    let (info, mut reader) = decoder.read_info().unwrap();
+    let mut pixels = vec![0; info.buffer_size()];
+    reader.next_frame(&mut pixels).unwrap();
+    (info.width, info.height, pixels)
+}
+
+#[test]
+fn answers_every_packet() {
+    let mut printer = printer();
+    // Bytes before the magic number are ignored.
+    assert_eq!(printer.exchange(0x12), 0);
+    assert_eq!(send(&mut printer, INIT, 0, &[]), (0x81, 0x00));
+    assert_eq!(send(&mut printer, STATUS, 0, &[]), (0x81, 0x00));
+    assert_eq!(send(&mut printer, DATA, 0, &band()), (0x81, 0x08));
+    // An empty data packet ends the image.
+    assert_eq!(send(&mut printer, DATA, 0, &[]), (0x81, 0x08));
+    assert_eq!(send(&mut printer, INIT, 0, &[]), (0x81, 0x00));
+}
+
+#[test]
+fn reports_a_full_buffer() {
+    let mut printer = printer();
+    for _ in 0..8 {
+        assert_eq!(send(&mut printer, DATA, 0, &band()), (0x81, 0x08));
+    }
+    assert_eq!(send(&mut printer, DATA, 0, &band()), (0x81, 0x0c));
+}
+
+#[test]
+fn checksum_errors() {
+    let mut printer = printer();
+    assert_eq!(send_with_checksum(&mut printer, DATA, 0, &band(), 0x1234), (0x81, 0x01));
+    // The bad packet's data was thrown away.
+    assert_eq!(send(&mut printer, STATUS, 0, &[]), (0x81, 0x00));
+    assert_eq!(send(&mut printer, DATA, 0, &band()), (0x81, 0x08));
+}
+
+#[test]
+fn prints_to_png() {
+    let dir = output_dir("printer-png");
+    let mut printer = Printer::new(dir.clone());
+    send(&mut printer, INIT, 0, &[]);
+    send(&mut printer, DATA, 0, &band());
+    // One sheet, a tile row of margin after, the default palette.
+    assert_eq!(send(&mut printer, PRINT, 0, &[1, 0x01, 0xe4, 0x40]), (0x81, 0x02));
+    assert_eq!(send(&mut printer, STATUS, 0, &[]), (0x81, 0x02));
+    assert_eq!(send(&mut printer, STATUS, 0, &[]), (0x81, 0x00));
+    assert_eq!(printer.num_prints(), 1);
+
+    let (width, height, pixels) = read_png(&dir.join("print-000.png"));
+    assert_eq!((width, height), (160, 24));
+    assert_eq!(pixels[0], 0x00);
+    assert_eq!(pixels[8], 0xff);
+    assert_eq!(pixels[8 * 160], 0xff);
+
+    // The same band, compressed: a run of 16 0xff bytes, then runs of
+    // zeroes to fill it out.
+    send(&mut printer, DATA, 1, &[0x8e, 0xff, 0xff, 0x00, 0xff, 0x00, 0xff, 0x00, 0xff, 0x00, 0xea, 0x00]);
+    send(&mut printer, PRINT, 0, &[1, 0x01, 0xe4, 0x40]);
+    assert_eq!(printer.num_prints(), 2);
+    assert!(read_png(&dir.join("print-001.png")).2 == pixels);
+    fs::remove_dir_all(&dir).unwrap();
+}
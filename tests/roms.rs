@@ -0,0 +1,204 @@
+// Runs a directory of test ROMs headless and prints a markdown table of
+// the results, so accuracy can be tracked over time. Point TEST_ROMS
+// at the directory (it is searched recursively); set TEST_ROMS_SUMMARY
+// to also write the table to a file, and TEST_ROMS_SECONDS to change
+// how long each ROM may run. Individual failures don't fail the test.
+//
+// Each ROM is judged by whichever protocol it uses:
+//   - ROMs with a reference image next to them (rom.gb and rom.png,
+//     e.g. dmg-acid2) pass if the screen matches it once they execute
+//     LD B,B or run out of time.
+//   - Mooneye ROMs, anywhere under a directory with "mooneye" in its
+//     name, execute LD B,B when they finish, and pass if B-L hold the
+//     Fibonacci numbers 3, 5, 8, 13, 21, 34.
+//   - Everything else is taken to be a Blargg ROM, which prints
+//     "Passed" or "Failed" over the serial port. These can execute
+//     LD B,B as an ordinary instruction.
+
+extern crate gamebust;
+extern crate png;
+
+use std::fs::File;
+use std::io::Write;
+use std::panic::{self,AssertUnwindSafe};
+use std::path::{Path,PathBuf};
+
+use png::HasParameters;
+
+use gamebust::bus::MemoryBus;
+use gamebust::cpu::Cpu;
+use gamebust::debugger::DebugState;
+use gamebust::registers::Reg8;
+use gamebust::serial::Sink;
+use gamebust::{SCREEN_WIDTH,SCREEN_HEIGHT,SCREEN_BUFFER_SIZE};
+
+const CYCLES_PER_SECOND: u64 = 4_194_304;
+const DEFAULT_SECONDS: u64 = 60;
+const LD_B_B: u8 = 0x40;
+const FIBONACCI: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+#[derive(Copy,Clone,Debug)]
+enum Protocol {
+    Screen,
+    Mooneye,
+    Serial,
+}
+
+enum Outcome {
+    Pass,
+    Fail(String),
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().is_some_and(|ext| ext == "gb") {
+            roms.push(path);
+        }
+    }
+}
+
+// Buckets a brightness into one of the four shades, so screens can be
+// compared whatever palette produced them.
+fn shade(brightness: u8) -> u8 {
+    match brightness {
+        0xe0 ..= 0xff => 0,
+        0x90 ..= 0xdf => 1,
+        0x30 ..= 0x8f => 2,
+        _ => 3,
+    }
+}
+
+fn load_reference(path: &Path) -> Result<Vec<u8>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set(png::Transformations::EXPAND);
+    let (info, mut reader) = decoder.read_info().map_err(|e| e.to_string())?;
+    if info.width as usize != SCREEN_WIDTH || info.height as usize != SCREEN_HEIGHT {
+        return Err(format!("reference image is {}x{}", info.width, info.height));
+    }
+    let mut buffer = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+    let channels = buffer.len() / (SCREEN_WIDTH * SCREEN_HEIGHT);
+    Ok(buffer.chunks(channels).map(|pixel| shade(pixel[0])).collect())
+}
+
+fn compare_screen(cpu: &Cpu, reference: &Path) -> Outcome {
+    let expected = match load_reference(reference) {
+        Ok(expected) => expected,
+        Err(e) => return Outcome::Fail(format!("couldn't load {}: {}", reference.display(), e)),
+    };
+    let mut buffer = [0; SCREEN_BUFFER_SIZE];
+    cpu.fill_screen_buffer(&mut buffer);
+    let actual = buffer.chunks(3).map(|pixel| shade(pixel[0]));
+    let differences = actual.zip(expected.iter()).filter(|&(a, &e)| a != e).count();
+    if differences == 0 {
+        Outcome::Pass
+    } else {
+        Outcome::Fail(format!("{} pixels differ", differences))
+    }
+}
+
+fn protocol(path: &Path) -> Protocol {
+    let mooneye = path.components()
+        .any(|c| c.as_os_str().to_string_lossy().to_lowercase().contains("mooneye"));
+    if path.with_extension("png").exists() {
+        Protocol::Screen
+    } else if mooneye {
+        Protocol::Mooneye
+    } else {
+        Protocol::Serial
+    }
+}
+
+fn run_rom(path: &Path, seconds: u64) -> (Protocol, Outcome) {
+    let protocol = protocol(path);
+    let reference = path.with_extension("png");
+    let mut cpu = Cpu::new(path);
+    cpu.bus.set_serial_sink(Sink::Buffer(Vec::new()));
+    let mut debug = DebugState::new();
+
+    let limit = seconds * CYCLES_PER_SECOND;
+    let mut cycles = 0;
+    let mut finished = false;
+    while cycles < limit {
+        if let Protocol::Screen | Protocol::Mooneye = protocol {
+            if cpu.bus.peek(cpu.reg.pc) == LD_B_B {
+                finished = true;
+                break;
+            }
+        }
+        cycles += cpu.step(&mut debug) as u64;
+        if let (Protocol::Serial, true) = (protocol, cpu.needs_redraw()) {
+            let output = String::from_utf8_lossy(cpu.bus.serial_output()).into_owned();
+            if output.contains("Passed") {
+                return (Protocol::Serial, Outcome::Pass);
+            } else if output.contains("Failed") {
+                let line = output.lines().rev().find(|l| !l.trim().is_empty()).unwrap_or("");
+                return (Protocol::Serial, Outcome::Fail(line.trim().to_string()));
+            }
+        }
+    }
+
+    let outcome = match protocol {
+        Protocol::Screen => compare_screen(&cpu, &reference),
+        Protocol::Mooneye if finished => {
+            let regs = [Reg8::B, Reg8::C, Reg8::D, Reg8::E, Reg8::H, Reg8::L];
+            let values: Vec<u8> = regs.iter().map(|&r| cpu.reg.r8(r)).collect();
+            if values[..] == FIBONACCI[..] {
+                Outcome::Pass
+            } else {
+                Outcome::Fail(format!("registers {:02x?}", values))
+            }
+        },
+        Protocol::Mooneye | Protocol::Serial => Outcome::Fail(format!("timed out after {}s", seconds)),
+    };
+    (protocol, outcome)
+}
+
+#[test]
+fn test_roms() {
+    let dir = match ::std::env::var("TEST_ROMS") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => {
+            println!("TEST_ROMS is not set; skipping");
+            return;
+        }
+    };
+    let seconds = ::std::env::var("TEST_ROMS_SECONDS").ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_SECONDS);
+
+    let mut roms = Vec::new();
+    find_roms(&dir, &mut roms);
+    roms.sort();
+
+    let mut summary = String::new();
+    summary.push_str("| ROM | Protocol | Result |\n");
+    summary.push_str("|-----|----------|--------|\n");
+    let mut passed = 0;
+    for rom in roms.iter() {
+        let name = rom.strip_prefix(&dir).unwrap_or(rom).display().to_string();
+        // Unsupported cartridges and opcodes panic, which counts as a failure.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| run_rom(rom, seconds)));
+        let (protocol, result) = match result {
+            Ok((protocol, Outcome::Pass)) => { passed += 1; (format!("{:?}", protocol), "pass".to_string()) },
+            Ok((protocol, Outcome::Fail(reason))) => (format!("{:?}", protocol), format!("FAIL: {}", reason)),
+            Err(_) => ("-".to_string(), "FAIL: crashed".to_string()),
+        };
+        summary.push_str(&format!("| {} | {} | {} |\n", name, protocol, result));
+    }
+    summary.push_str(&format!("\n{}/{} passed\n", passed, roms.len()));
+
+    println!("{}", summary);
+    if let Ok(path) = ::std::env::var("TEST_ROMS_SUMMARY") {
+        let mut file = File::create(&path).expect("Couldn't create summary file");
+        file.write_all(summary.as_bytes()).expect("Couldn't write summary file");
+    }
+}
@@ -0,0 +1,64 @@
+extern crate gamebust;
+
+mod common;
+
+use std::fs::{self,File};
+
+use gamebust::cpu::Interrupt;
+use gamebust::debugger::DebugState;
+use gamebust::serial::{Serial,Sink};
+
+use common::game_boy;
+
+// Starts sending each byte of `text`, without waiting for it to go.
+fn send(serial: &mut Serial, text: &[u8]) {
+    for &byte in text {
+        serial.set_transfer(byte);
+        serial.set_control(0x81);
+        serial.update(8 * 512, &mut Interrupt::empty());
+    }
+}
+
+#[test]
+fn buffer_sink() {
+    let mut serial = Serial::new();
+    serial.set_sink(Sink::Buffer(Vec::new()));
+    send(&mut serial, b"ok");
+    // Only starting a transfer counts, whichever side has the clock.
+    serial.set_transfer(b'x');
+    serial.set_control(0x01);
+    serial.set_control(0x80);
+    assert_eq!(serial.get_sink().contents(), b"okx");
+}
+
+#[test]
+fn file_sink() {
+    let path = ::std::env::temp_dir().join(format!("gamebust-serial-{}", ::std::process::id()));
+    let mut serial = Serial::new();
+    serial.set_sink(Sink::File(File::create(&path).unwrap()));
+    send(&mut serial, b"Passed");
+    // Only buffers keep what was sent to them.
+    assert_eq!(serial.get_sink().contents(), b"");
+    drop(serial);
+    assert_eq!(fs::read(&path).unwrap(), b"Passed");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn no_sink() {
+    let mut serial = Serial::new();
+    send(&mut serial, b"lost");
+    assert_eq!(serial.get_sink().contents(), b"");
+}
+
+#[test]
+fn game_output_reaches_the_sink() {
+    // LD A,'A'; LDH (0x01),A; LD A,0x81; LDH (0x02),A; JR -2
+    let mut cpu = game_boy(&[0x3e, b'A', 0xe0, 0x01, 0x3e, 0x81, 0xe0, 0x02, 0x18, 0xfe]);
+    cpu.bus.set_serial_sink(Sink::Buffer(Vec::new()));
+    let mut debug = DebugState::new();
+    for _ in 0..5 {
+        cpu.step(&mut debug);
+    }
+    assert_eq!(cpu.bus.serial_output(), b"A");
+}
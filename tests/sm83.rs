@@ -0,0 +1,146 @@
+// Runs the SM83 single-instruction tests
+// (https://github.com/SingleStepTests/sm83) against the CPU. Each file
+// holds the tests for one opcode; every test gives the registers and
+// memory before and after the instruction, and the bus activity on
+// each machine cycle. The suite is large, so it isn't included here:
+// point SM83_TESTS at a checkout of its v1 directory to run it.
+
+extern crate gamebust;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+use serde_json::Value;
+
+use gamebust::bus::{BusCycle,FlatBus,MemoryBus};
+use gamebust::cpu::Cpu;
+use gamebust::debugger::DebugState;
+use gamebust::registers::{Reg8,Reg16};
+
+fn field(state: &Value, name: &str) -> u16 {
+    state[name].as_u64().unwrap_or_else(|| panic!("Missing field {}", name)) as u16
+}
+
+fn set_state(cpu: &mut Cpu<FlatBus>, state: &Value) {
+    cpu.reg.w16(Reg16::AF, field(state, "a") << 8 | field(state, "f"));
+    cpu.reg.w16(Reg16::BC, field(state, "b") << 8 | field(state, "c"));
+    cpu.reg.w16(Reg16::DE, field(state, "d") << 8 | field(state, "e"));
+    cpu.reg.w16(Reg16::HL, field(state, "h") << 8 | field(state, "l"));
+    cpu.reg.sp = field(state, "sp");
+    cpu.reg.pc = field(state, "pc");
+    cpu.set_interrupts_enabled(field(state, "ime") != 0);
+    for entry in state["ram"].as_array().expect("Missing ram") {
+        let addr = entry[0].as_u64().unwrap() as u16;
+        cpu.bus.load(addr, &[entry[1].as_u64().unwrap() as u8]);
+    }
+}
+
+// Returns a description of the first difference from the final state.
+fn check_state(cpu: &Cpu<FlatBus>, state: &Value, cycles: &Value) -> Result<(), String> {
+    let regs = [("a", Reg8::A), ("b", Reg8::B), ("c", Reg8::C), ("d", Reg8::D),
+                ("e", Reg8::E), ("h", Reg8::H), ("l", Reg8::L)];
+    for &(name, reg) in regs.iter() {
+        let actual = cpu.reg.r8(reg) as u16;
+        if actual != field(state, name) {
+            return Err(format!("{}: expected {:02x}, got {:02x}", name, field(state, name), actual));
+        }
+    }
+    let f = cpu.reg.r16(Reg16::AF) & 0xff;
+    if f != field(state, "f") {
+        return Err(format!("f: expected {:02x}, got {:02x}", field(state, "f"), f));
+    }
+    if cpu.reg.sp != field(state, "sp") {
+        return Err(format!("sp: expected {:04x}, got {:04x}", field(state, "sp"), cpu.reg.sp));
+    }
+    if cpu.reg.pc != field(state, "pc") {
+        return Err(format!("pc: expected {:04x}, got {:04x}", field(state, "pc"), cpu.reg.pc));
+    }
+    if cpu.interrupts_enabled() != (field(state, "ime") != 0) {
+        return Err(format!("ime: expected {}, got {}", field(state, "ime"), cpu.interrupts_enabled()));
+    }
+    for entry in state["ram"].as_array().expect("Missing ram") {
+        let addr = entry[0].as_u64().unwrap() as u16;
+        let expected = entry[1].as_u64().unwrap() as u8;
+        if cpu.bus.peek(addr) != expected {
+            return Err(format!("({:04x}): expected {:02x}, got {:02x}",
+                               addr, expected, cpu.bus.peek(addr)));
+        }
+    }
+
+    let expected_cycles = cycles.as_array().expect("Missing cycles");
+    if expected_cycles.len() != cpu.bus.cycles().len() {
+        return Err(format!("expected {} cycles, got {}", expected_cycles.len(), cpu.bus.cycles().len()));
+    }
+    for (i, (expected, &actual)) in expected_cycles.iter().zip(cpu.bus.cycles().iter()).enumerate() {
+        // Each cycle is [address, data, pins], where the pins are
+        // "r-m" for a read, "-wm" for a write and "---" otherwise.
+        let pins = expected[2].as_str().unwrap_or("---");
+        let addr = expected[0].as_u64().unwrap_or(0) as u16;
+        let data = expected[1].as_u64().unwrap_or(0) as u8;
+        let expected = if pins.starts_with('r') {
+            BusCycle::Read(addr, data)
+        } else if pins.contains('w') {
+            BusCycle::Write(addr, data)
+        } else {
+            BusCycle::Internal
+        };
+        if expected != actual {
+            return Err(format!("cycle {}: expected {:?}, got {:?}", i, expected, actual));
+        }
+    }
+    Ok(())
+}
+
+fn run_test(test: &Value) -> Result<(), String> {
+    let mut cpu = Cpu::with_bus(FlatBus::new());
+    set_state(&mut cpu, &test["initial"]);
+    cpu.step(&mut DebugState::new());
+    check_state(&cpu, &test["final"], &test["cycles"])
+}
+
+#[test]
+fn sm83() {
+    let dir = match ::std::env::var("SM83_TESTS") {
+        Ok(dir) => dir,
+        Err(_) => {
+            println!("SM83_TESTS is not set; skipping");
+            return;
+        }
+    };
+    let mut paths: Vec<_> = Path::new(&dir).read_dir().expect("Couldn't read SM83_TESTS")
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+
+    // Opcode -> (passed, failed)
+    let mut results = BTreeMap::new();
+    for path in paths {
+        let opcode = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let file = File::open(&path).expect("Couldn't open test file");
+        let tests: Value = serde_json::from_reader(file).expect("Couldn't parse test file");
+        let mut passed = 0;
+        let mut failed = 0;
+        for test in tests.as_array().expect("Expected a list of tests") {
+            match run_test(test) {
+                Ok(()) => passed += 1,
+                Err(e) => {
+                    if failed == 0 {
+                        println!("{}: {}", test["name"].as_str().unwrap_or(&opcode), e);
+                    }
+                    failed += 1;
+                }
+            }
+        }
+        results.insert(opcode, (passed, failed));
+    }
+
+    let mut total_failed = 0;
+    for (opcode, &(passed, failed)) in results.iter() {
+        println!("{:>5}: {:5} passed {:5} failed", opcode, passed, failed);
+        total_failed += failed;
+    }
+    assert!(total_failed == 0, "{} tests failed", total_failed);
+}
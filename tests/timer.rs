@@ -0,0 +1,106 @@
+extern crate gamebust;
+
+use gamebust::cpu::Interrupt;
+use gamebust::timer::Timer;
+
+// A timer that counts every 4 machine cycles, off bit 3 of the system
+// counter, which is set after the first 2.
+fn fast_timer() -> Timer {
+    let mut timer = Timer::new();
+    timer.set_control(0x05);
+    timer
+}
+
+fn ticks(timer: &mut Timer, n: u32) -> Interrupt {
+    let mut irq = Interrupt::empty();
+    timer.update(n * 4, &mut irq);
+    irq
+}
+
+#[test]
+fn counts_on_falling_edges() {
+    let mut timer = fast_timer();
+    ticks(&mut timer, 3);
+    assert_eq!(timer.get_counter(), 0);
+    ticks(&mut timer, 1);
+    assert_eq!(timer.get_counter(), 1);
+    ticks(&mut timer, 8);
+    assert_eq!(timer.get_counter(), 3);
+    assert_eq!(timer.get_system_counter(), 48);
+    assert_eq!(timer.get_divider(), 0);
+    ticks(&mut timer, 64);
+    assert_eq!(timer.get_divider(), 1);
+}
+
+#[test]
+fn div_write_glitch() {
+    let mut timer = fast_timer();
+    ticks(&mut timer, 2);
+    // Resetting the counter while the selected bit is high is a
+    // falling edge.
+    timer.reset_divider();
+    assert_eq!(timer.get_counter(), 1);
+    assert_eq!(timer.get_system_counter(), 0);
+    // With the bit low it isn't.
+    timer.reset_divider();
+    assert_eq!(timer.get_counter(), 1);
+}
+
+#[test]
+fn tac_change_glitch() {
+    let mut timer = fast_timer();
+    ticks(&mut timer, 2);
+    // Selecting a bit that is low while the old one was high...
+    timer.set_control(0x04);
+    assert_eq!(timer.get_counter(), 1);
+    // ...or disabling the timer both tick it.
+    timer.set_control(0x05);
+    ticks(&mut timer, 4);
+    assert_eq!(timer.get_counter(), 2);
+    timer.set_control(0x01);
+    assert_eq!(timer.get_counter(), 3);
+}
+
+// Runs a timer about to overflow to TIMA = 0, before the reload.
+fn overflowed_timer() -> Timer {
+    let mut timer = fast_timer();
+    timer.set_counter(0xff);
+    timer.set_modulo(0x42);
+    assert!(ticks(&mut timer, 4).is_empty());
+    timer
+}
+
+#[test]
+fn reload_is_delayed() {
+    let mut timer = overflowed_timer();
+    // TIMA reads 0 for a machine cycle, without an interrupt yet.
+    assert_eq!(timer.get_counter(), 0);
+    assert!(timer.reload_pending());
+    assert_eq!(ticks(&mut timer, 1), Interrupt::TIMER);
+    assert_eq!(timer.get_counter(), 0x42);
+    ticks(&mut timer, 1);
+    assert!(!timer.reload_pending());
+}
+
+#[test]
+fn writes_around_the_reload() {
+    // Writing TIMA before the reload cancels it and the interrupt.
+    let mut timer = overflowed_timer();
+    timer.set_counter(0x10);
+    assert!(!timer.reload_pending());
+    assert!(ticks(&mut timer, 1).is_empty());
+    assert_eq!(timer.get_counter(), 0x10);
+
+    // Writing TIMA on the reload cycle is ignored.
+    let mut timer = overflowed_timer();
+    ticks(&mut timer, 1);
+    timer.set_counter(0x10);
+    assert_eq!(timer.get_counter(), 0x42);
+
+    // Writing TMA on the reload cycle goes through to TIMA too.
+    let mut timer = overflowed_timer();
+    ticks(&mut timer, 1);
+    timer.set_modulo(0x55);
+    assert_eq!(timer.get_counter(), 0x55);
+    assert_eq!(timer.get_modulo(), 0x55);
+}
@@ -0,0 +1,69 @@
+extern crate gamebust;
+
+mod common;
+
+use std::fs;
+
+use gamebust::debugger::DebugState;
+use gamebust::registers::Reg16;
+use gamebust::trace::{Trace,Trigger};
+
+use common::cpu_with;
+
+// Traces `steps` instructions of `program` and returns the lines written.
+fn trace(program: &[u8], steps: usize, start: Option<Trigger>, stop: Option<Trigger>) -> Vec<String> {
+    let path = ::std::env::temp_dir().join(format!("gamebust-trace-{}-{:?}-{:?}",
+                                                   ::std::process::id(), start, stop));
+    let mut cpu = cpu_with(program);
+    cpu.reg.w16(Reg16::AF, 0x01b0);
+    cpu.reg.w16(Reg16::BC, 0x0013);
+    cpu.reg.w16(Reg16::DE, 0x00d8);
+    cpu.reg.w16(Reg16::HL, 0x014d);
+    cpu.set_trace(Some(Trace::new(&path, start, stop).unwrap()));
+    let mut debug = DebugState::new();
+    for _ in 0..steps {
+        cpu.step(&mut debug);
+    }
+    // Dropping the trace flushes it.
+    cpu.set_trace(None);
+    let text = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    text.lines().map(|line| line.to_string()).collect()
+}
+
+// The PC of each line.
+fn pcs(lines: &[String]) -> Vec<&str> {
+    lines.iter().map(|line| &line[line.find("PC:").unwrap() + 3..][..4]).collect()
+}
+
+#[test]
+fn gameboy_doctor_format() {
+    // LD A,0x12; LD B,0x34
+    let lines = trace(&[0x3e, 0x12, 0x06, 0x34], 2, None, None);
+    assert_eq!(lines, vec![
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:3E,12,06,34",
+        "A:12 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0102 PCMEM:06,34,00,00",
+    ]);
+}
+
+#[test]
+fn parse_triggers() {
+    assert_eq!(Trigger::parse("0x0150"), Some(Trigger::Pc(0x150)));
+    assert_eq!(Trigger::parse("5000"), Some(Trigger::Instructions(5000)));
+    assert_eq!(Trigger::parse("0x10000"), None);
+    assert_eq!(Trigger::parse("0xzz"), None);
+    assert_eq!(Trigger::parse("start"), None);
+}
+
+#[test]
+fn start_and_stop() {
+    let nops = [0x00; 8];
+    let lines = trace(&nops, 8, Trigger::parse("0x0102"), Trigger::parse("4"));
+    assert_eq!(pcs(&lines), vec!["0102", "0103"]);
+    let lines = trace(&nops, 8, Trigger::parse("1"), Trigger::parse("0x0104"));
+    assert_eq!(pcs(&lines), vec!["0101", "0102", "0103"]);
+    // Tracing doesn't start again when the loop comes round: NOP; NOP;
+    // NOP; JR -5
+    let lines = trace(&[0x00, 0x00, 0x00, 0x18, 0xfb], 8, None, Trigger::parse("0x0102"));
+    assert_eq!(pcs(&lines), vec!["0100", "0101"]);
+}